@@ -1,7 +1,8 @@
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use crossbeam_utils::sync::WaitGroup;
+use kvs::thread_pool::{RayonThreadPool, SharedQueueThreadPool, ThreadPool};
 use kvs::{KvStore, KvsEngine, SledKvsEngine};
 use rand::prelude::*;
-use sled;
 use tempfile::TempDir;
 
 
@@ -13,7 +14,7 @@ fn set_bench(c: &mut Criterion) {
                 let temp_dir = TempDir::new().unwrap();
                 (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
             },
-            |(mut store, _temp_dir)| {
+            |(store, _temp_dir)| {
                 for i in 1..(1 << 12) {
                     store.set(format!("key{}", i), "value".to_string()).unwrap();
                 }
@@ -25,9 +26,9 @@ fn set_bench(c: &mut Criterion) {
         b.iter_batched(
             || {
                 let temp_dir = TempDir::new().unwrap();
-                (SledKvsEngine::new(sled::open(&temp_dir).unwrap()), temp_dir)
+                (SledKvsEngine::new(sled::open(&temp_dir).unwrap()).unwrap(), temp_dir)
             },
-            |(mut db, _temp_dir)| {
+            |(db, _temp_dir)| {
                 for i in 1..(1 << 12) {
                     db.set(format!("key{}", i), "value".to_string()).unwrap();
                 }
@@ -42,34 +43,31 @@ fn set_bench(c: &mut Criterion) {
 
 fn get_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("get_bench");
-    for i in &vec![8, 12, 16, 20] {
+    for i in &[8, 12, 16, 20] {
         group.bench_with_input(format!("kvs_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
-            let mut store = KvStore::open(temp_dir.path()).unwrap();
-            for key_i in 1..(1 << i) {
-                store
-                    .set(format!("key{}", key_i), "value".to_string())
-                    .unwrap();
+            let store = KvStore::open(temp_dir.path()).unwrap();
+            let keys: Vec<String> = (1..(1 << i)).map(|key_i| format!("key{}", key_i)).collect();
+            for key in &keys {
+                store.set(key.as_str(), "value").unwrap();
             }
             let mut rng = SmallRng::from_seed([0; 16]);
             b.iter(|| {
-                store
-                    .get(format!("key{}", rng.gen_range(1, 1 << i)))
-                    .unwrap();
+                store.get(&keys[rng.gen_range(0, keys.len())]).unwrap();
             })
         });
     }
-    for i in &vec![8, 12, 16, 20] {
+    for i in &[8, 12, 16, 20] {
         group.bench_with_input(format!("sled_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
-            let mut db = SledKvsEngine::new(sled::open(&temp_dir).unwrap());
-            for key_i in 1..(1 << i) {
-                db.set(format!("key{}", key_i), "value".to_string())
-                    .unwrap();
+            let db = SledKvsEngine::new(sled::open(&temp_dir).unwrap()).unwrap();
+            let keys: Vec<String> = (1..(1 << i)).map(|key_i| format!("key{}", key_i)).collect();
+            for key in &keys {
+                db.set(key.as_str(), "value").unwrap();
             }
             let mut rng = SmallRng::from_seed([0; 16]);
             b.iter(|| {
-                db.get(format!("key{}", rng.gen_range(1, 1 << i))).unwrap();
+                db.get(&keys[rng.gen_range(0, keys.len())]).unwrap();
             })
         });
     }
@@ -77,7 +75,52 @@ fn get_bench(c: &mut Criterion) {
 }
 
 
-criterion_group!(benches, set_bench, get_bench);
+fn pool_set<E: KvsEngine, P: ThreadPool>(engine: &E, pool: &P) {
+    let wg = WaitGroup::new();
+    for i in 1..(1 << 10) {
+        let engine = engine.clone();
+        let wg = wg.clone();
+        pool.spawn(move || {
+            engine.set(format!("key{}", i), "value".to_string()).unwrap();
+            drop(wg);
+        });
+    }
+    wg.wait();
+}
+
+
+fn pool_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pool_bench");
+    let threads = num_cpus::get() as u32;
+    group.bench_function("kvs_shared_queue", |b| {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        let pool = SharedQueueThreadPool::new(threads).unwrap();
+        b.iter(|| pool_set(&store, &pool))
+    });
+    group.bench_function("kvs_rayon", |b| {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        let pool = RayonThreadPool::new(threads).unwrap();
+        b.iter(|| pool_set(&store, &pool))
+    });
+    group.bench_function("sled_shared_queue", |b| {
+        let temp_dir = TempDir::new().unwrap();
+        let db = SledKvsEngine::new(sled::open(&temp_dir).unwrap()).unwrap();
+        let pool = SharedQueueThreadPool::new(threads).unwrap();
+        b.iter(|| pool_set(&db, &pool))
+    });
+    group.bench_function("sled_rayon", |b| {
+        let temp_dir = TempDir::new().unwrap();
+        let db = SledKvsEngine::new(sled::open(&temp_dir).unwrap()).unwrap();
+        let pool = RayonThreadPool::new(threads).unwrap();
+        b.iter(|| pool_set(&db, &pool))
+    });
+    group.finish();
+}
+
+
+criterion_group!(benches, set_bench, get_bench, pool_bench);
 criterion_main!(benches);
 
 
@@ -0,0 +1,324 @@
+use clap::AppSettings;
+use kvs::{
+    write_engine_file, DirLock, Engine, KvStore, KvsEngine, KvsError, Result, SledKvsEngine,
+    VerifyReport,
+};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+use structopt::StructOpt;
+
+// chunks of the dump buffered between the two engines of a migration
+const PIPE_CAPACITY: usize = 16;
+
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs-admin",
+    raw(global_settings = "&[\
+                           AppSettings::DisableHelpSubcommand,\
+                           AppSettings::VersionlessSubcommands]")
+)]
+struct Opt {
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(name = "dump", about = "Write the key/value pairs of a data directory to stdout")]
+    Dump {
+        #[structopt(name = "DIR", help = "The data directory", parse(from_os_str))]
+        dir: PathBuf,
+        #[structopt(
+            long,
+            help = "Sets the storage engine [default: the one recorded in the directory, else kvs]",
+            raw(possible_values = "Engine::NAMES")
+        )]
+        engine: Option<Engine>,
+    },
+
+    #[structopt(
+        name = "restore",
+        about = "Write the key/value pairs of a dump read from stdin into a data directory"
+    )]
+    Restore {
+        #[structopt(name = "DIR", help = "The data directory, created if missing", parse(from_os_str))]
+        dir: PathBuf,
+        #[structopt(
+            long,
+            help = "Sets the storage engine [default: the one recorded in the directory, else kvs]",
+            raw(possible_values = "Engine::NAMES")
+        )]
+        engine: Option<Engine>,
+    },
+
+    #[structopt(
+        name = "migrate",
+        about = "Copy the key/value pairs of a data directory into a new one, of another engine"
+    )]
+    Migrate {
+        #[structopt(name = "SOURCE", help = "The data directory to read", parse(from_os_str))]
+        source: PathBuf,
+        #[structopt(
+            name = "DEST",
+            help = "The data directory to create, removed if the migration fails",
+            parse(from_os_str)
+        )]
+        dest: PathBuf,
+        #[structopt(
+            long,
+            help = "Sets the storage engine of the source directory",
+            raw(possible_values = "Engine::NAMES")
+        )]
+        from: Engine,
+        #[structopt(
+            long,
+            help = "Sets the storage engine of the new directory",
+            raw(possible_values = "Engine::NAMES")
+        )]
+        to: Engine,
+    },
+
+    #[structopt(
+        name = "verify",
+        about = "Check the log files of a kvs data directory, exiting with an error if one is damaged"
+    )]
+    Verify {
+        #[structopt(name = "DIR", help = "The data directory", parse(from_os_str))]
+        dir: PathBuf,
+    },
+}
+
+
+/// Opens the engine in `$dir`, binds it to `$name` and evaluates `$body`.
+///
+/// The engines are different types, hence a macro rather than a function.
+macro_rules! with_engine {
+    ($engine:expr, $dir:expr, $read_only:expr, $name:ident => $body:expr) => {
+        match $engine {
+            Engine::Kvs => {
+                let $name = if $read_only {
+                    KvStore::open_read_only($dir)?
+                } else {
+                    KvStore::open($dir)?
+                };
+                $body
+            }
+            Engine::Sled => {
+                // sled cannot be opened read-only, the lock still keeps servers away
+                let _lock = if $read_only {
+                    DirLock::acquire_shared($dir)?
+                } else {
+                    fs::create_dir_all($dir)?;
+                    Some(DirLock::acquire($dir)?)
+                };
+                // a source is left as it is, expired keys included
+                let $name = if $read_only {
+                    SledKvsEngine::without_sweeper(sled::open($dir)?)?
+                } else {
+                    SledKvsEngine::new(sled::open($dir)?)?
+                };
+                $body
+            }
+        }
+    };
+}
+
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+
+fn run(opt: Opt) -> Result<()> {
+    match opt.command {
+        Command::Dump { dir, engine } => {
+            if !dir.is_dir() {
+                return Err(KvsError::KvPathNotFoundError);
+            }
+            let engine = check_engine(&dir, engine)?;
+            let stdout = io::stdout();
+            with_engine!(engine, &dir, true, source => kvs::dump(&source, stdout.lock())?);
+        }
+        Command::Restore { dir, engine } => {
+            let engine = check_engine(&dir, engine)?;
+            let stdin = io::stdin();
+            // recorded while the engine still holds the directory lock
+            let count = with_engine!(engine, &dir, false, target => {
+                let count = kvs::restore(&target, stdin.lock())?;
+                write_engine_file(&dir, engine)?;
+                count
+            });
+            println!("Restored {} keys", count);
+        }
+        Command::Migrate {
+            source,
+            dest,
+            from,
+            to,
+        } => {
+            if !source.is_dir() {
+                return Err(KvsError::KvPathNotFoundError);
+            }
+            check_engine(&source, Some(from))?;
+            // the destination must be new, so that no older data is mixed in
+            fs::create_dir(&dest)?;
+            let count = match migrate_dir(&source, &dest, from, to) {
+                Ok(count) => count,
+                Err(e) => {
+                    // a half-filled directory must not be mistaken for a copy
+                    if let Err(e) = fs::remove_dir_all(&dest) {
+                        eprintln!("{} cannot be removed: {}", dest.display(), e);
+                    }
+                    return Err(e);
+                }
+            };
+            println!("Migrated {} keys from {} to {}", count, from, to);
+        }
+        Command::Verify { dir } => {
+            check_engine(&dir, Some(Engine::Kvs))?;
+            let report = KvStore::verify(&dir)?;
+            print_report(&report);
+            if !report.is_clean() {
+                eprintln!("Damaged log files found");
+                exit(1);
+            }
+        }
+    }
+    Ok(())
+}
+
+
+fn print_report(report: &VerifyReport) {
+    let mut orphaned = 0;
+    for gen in &report.generations {
+        let mut line = format!(
+            "{}.log: {} bytes, {} live, {} garbage, {} keys",
+            gen.gen, gen.size, gen.live_bytes, gen.garbage_bytes, gen.keys
+        );
+        if gen.hint {
+            line.push_str(", hint");
+        }
+        if gen.orphaned {
+            line.push_str(", orphaned");
+            orphaned += 1;
+        }
+        if gen.needs_upgrade {
+            line.push_str(", needs upgrade");
+        }
+        println!("{}", line);
+        if let Some(repair) = &gen.corruption {
+            println!(
+                "  damaged at offset {}: {}, {} bytes unreadable",
+                repair.offset, repair.reason, repair.bytes_dropped
+            );
+        }
+    }
+    println!(
+        "{} generations ({} orphaned), {} keys, {} expired",
+        report.generations.len(),
+        orphaned,
+        report.keys,
+        report.expired_keys
+    );
+}
+
+
+/// Copies the data directory `source` into the new directory `dest`, and records
+/// its engine once done.
+fn migrate_dir(source: &Path, dest: &Path, from: Engine, to: Engine) -> Result<u64> {
+    let count = with_engine!(from, source, true, source =>
+        with_engine!(to, dest, false, target => {
+            let count = migrate(source, &target)?;
+            write_engine_file(dest, to)?;
+            count
+        })
+    );
+    Ok(count)
+}
+
+
+/// Dumps `source` into `target`, through a pipe rather than a file.
+fn migrate<S: KvsEngine, T: KvsEngine>(source: S, target: &T) -> Result<u64> {
+    let (tx, rx) = mpsc::sync_channel(PIPE_CAPACITY);
+    let dumper = thread::spawn(move || kvs::dump(&source, PipeWriter(tx)));
+    let restored = kvs::restore(target, PipeReader {
+        rx,
+        chunk: io::Cursor::new(Vec::new()),
+    });
+    let dumped = dumper
+        .join()
+        .unwrap_or_else(|_| Err(KvsError::StringError("The dump thread panicked".to_owned())));
+    match (restored, dumped) {
+        // the restore saw the dump end early, because the dump failed
+        (Err(KvsError::Corruption(_)), Err(e)) => Err(e),
+        (Err(e), _) | (Ok(_), Err(e)) => Err(e),
+        (Ok(count), Ok(_)) => Ok(count),
+    }
+}
+
+
+/// Returns the engine to open `dir` with, checking it against the `engine` file
+/// `kvs-server` writes.
+fn check_engine(dir: &Path, engine: Option<Engine>) -> Result<Engine> {
+    let recorded = match fs::read_to_string(dir.join("engine")) {
+        Ok(recorded) => Some(recorded.parse::<Engine>().map_err(KvsError::StringError)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    match (engine, recorded) {
+        (Some(engine), Some(recorded)) if engine != recorded => Err(KvsError::StringError(
+            format!("The directory holds data of the {} engine", recorded),
+        )),
+        (engine, recorded) => Ok(engine.or(recorded).unwrap_or(Engine::Kvs)),
+    }
+}
+
+
+struct PipeWriter(SyncSender<Vec<u8>>);
+
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the restore stopped"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+struct PipeReader {
+    rx: Receiver<Vec<u8>>,
+    chunk: io::Cursor<Vec<u8>>,
+}
+
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.chunk.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            match self.rx.recv() {
+                Ok(chunk) => self.chunk = io::Cursor::new(chunk),
+                // the dump is over, or failed
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
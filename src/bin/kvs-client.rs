@@ -1,7 +1,10 @@
 use clap::AppSettings;
-use kvs::{KvsClient, Result};
+use kvs::{KvsClient, Result, ScanOptions};
+use std::io::{self, Write};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
+use std::time::Duration;
 use structopt::StructOpt;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
@@ -43,6 +46,13 @@ enum Command {
         key: String,
         #[structopt(name = "VALUE", help = "The string value of the key")]
         value: String,
+        #[structopt(
+            long,
+            help = "Makes the key expire after the given duration, e.g. 500ms, 30s, 5m or 1h",
+            value_name = "DURATION",
+            parse(try_from_str = "parse_duration")
+        )]
+        ttl: Option<Duration>,
         #[structopt(
             long,
             help = "Sets the server address",
@@ -66,6 +76,83 @@ enum Command {
         )]
         addr: SocketAddr,
     },
+
+    #[structopt(
+        name = "cas",
+        about = "Set or remove a key only if its current value is the expected one"
+    )]
+    Cas {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(
+            long,
+            help = "Sets the expected current value [default: the key is missing]",
+            value_name = "VALUE"
+        )]
+        expected: Option<String>,
+        #[structopt(
+            long,
+            help = "Sets the new value [default: the key is removed]",
+            value_name = "VALUE"
+        )]
+        new: Option<String>,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+
+    #[structopt(name = "scan", about = "List the keys in a given range with their values")]
+    Scan {
+        #[structopt(name = "START", help = "The first key of the range [default: unbounded]")]
+        start: Option<String>,
+        #[structopt(name = "END", help = "The key ending the range, excluded [default: unbounded]")]
+        end: Option<String>,
+        #[structopt(
+            long,
+            help = "Lists the keys starting with the given prefix instead of a range",
+            value_name = "PREFIX",
+            raw(conflicts_with_all = r#"&["START", "END"]"#)
+        )]
+        prefix: Option<String>,
+        #[structopt(long, help = "Sets the maximum number of keys listed", value_name = "N")]
+        limit: Option<usize>,
+        #[structopt(long, help = "Lists the keys in descending order")]
+        reverse: bool,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+
+    #[structopt(
+        name = "checkpoint",
+        about = "Make the server copy its data to a new directory, while it keeps serving"
+    )]
+    Checkpoint {
+        #[structopt(
+            name = "DEST",
+            help = "The directory to create, on the server, relative to its checkpoint directory",
+            parse(from_os_str)
+        )]
+        dest: PathBuf,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
 }
 
 
@@ -83,20 +170,113 @@ fn run(opt: Opt) -> Result<()> {
         Command::Get { key, addr } => {
             let mut client = KvsClient::connect(addr)?;
             if let Some(value) = client.get(key)? {
-                println!("{}", value);
+                print_line(&[&value])?;
             } else {
                 println!("Key not found");
             }
         }
-        Command::Set { key, value, addr } => {
+        Command::Set {
+            key,
+            value,
+            ttl,
+            addr,
+        } => {
             let mut client = KvsClient::connect(addr)?;
-            client.set(key, value)?;
+            match ttl {
+                Some(ttl) => client.set_with_ttl(key, value, ttl)?,
+                None => client.set(key, value)?,
+            }
         }
         Command::Remove { key, addr } => {
             let mut client = KvsClient::connect(addr)?;
             client.remove(key)?;
         }
+        Command::Cas {
+            key,
+            expected,
+            new,
+            addr,
+        } => {
+            let mut client = KvsClient::connect(addr)?;
+            let expected = expected.map(String::into_bytes);
+            let new = new.map(String::into_bytes);
+            if !client.compare_and_swap(key, expected, new)? {
+                eprintln!("Current value does not match");
+                exit(1);
+            }
+        }
+        Command::Scan {
+            start,
+            end,
+            prefix,
+            limit,
+            reverse,
+            addr,
+        } => {
+            let mut options = ScanOptions::new().reverse(reverse);
+            if let Some(limit) = limit {
+                options = options.limit(limit);
+            }
+            let mut client = KvsClient::connect(addr)?;
+            let pairs = match prefix {
+                Some(prefix) => client.scan_prefix(prefix, options)?,
+                None => client.scan(
+                    start.map(String::into_bytes),
+                    end.map(String::into_bytes),
+                    options,
+                )?,
+            };
+            for (key, value) in pairs {
+                print_line(&[&key, b"\t", &value])?;
+            }
+        }
+        Command::Checkpoint { dest, addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            client.checkpoint(dest)?;
+        }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+
+/// Writes the parts and a newline to stdout, as they are since keys and values
+/// may not be valid UTF-8.
+fn print_line(parts: &[&[u8]]) -> Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for part in parts {
+        stdout.write_all(part)?;
+    }
+    stdout.write_all(b"\n")?;
+    Ok(())
+}
+
+
+/// Parses a duration made of a number and a unit among `ms`, `s`, `m` and `h`.
+/// A number alone is a number of seconds. Zero is rejected, the value would have
+/// expired already.
+fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration: {}", s))?;
+    let secs = |factor: u64| {
+        number
+            .checked_mul(factor)
+            .map(Duration::from_secs)
+            .ok_or_else(|| format!("invalid duration: {}", s))
+    };
+    let duration = match unit {
+        "ms" => Duration::from_millis(number),
+        "" | "s" => secs(1)?,
+        "m" => secs(60)?,
+        "h" => secs(60 * 60)?,
+        _ => return Err(format!("invalid duration unit: {}", unit)),
+    };
+    if duration == Duration::from_secs(0) {
+        return Err(format!("invalid duration: {}", s));
+    }
+    Ok(duration)
+}
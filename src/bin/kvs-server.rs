@@ -6,23 +6,96 @@ use log::{error, info, warn};
 use std::env;
 use std::env::current_dir;
 use std::fs;
+use std::fmt;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
+use std::str::FromStr;
+use std::time::Duration;
 use structopt::StructOpt;
 
 
 arg_enum! {
     #[allow(non_camel_case_types)]
     #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-    enum Engine {
-        kvs,
-        sled
+    enum Recovery {
+        strict,
+        truncate,
+        quarantine
+    }
+}
+
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum SyncMode {
+        never,
+        always,
+        every,
+        interval
+    }
+}
+
+
+impl From<Recovery> for RecoveryMode {
+    fn from(recovery: Recovery) -> RecoveryMode {
+        match recovery {
+            Recovery::strict => RecoveryMode::Strict,
+            Recovery::truncate => RecoveryMode::Truncate,
+            Recovery::quarantine => RecoveryMode::Quarantine,
+        }
+    }
+}
+
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Pool {
+    Naive,
+    SharedQueue,
+    Rayon,
+}
+
+
+const POOL_NAMES: &[&str] = &["naive", "shared-queue", "rayon"];
+
+
+impl FromStr for Pool {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "naive" => Ok(Pool::Naive),
+            "shared-queue" => Ok(Pool::SharedQueue),
+            "rayon" => Ok(Pool::Rayon),
+            _ => Err(format!("valid values: {}", POOL_NAMES.join(", "))),
+        }
+    }
+}
+
+
+impl fmt::Display for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Pool::Naive => "naive",
+            Pool::SharedQueue => "shared-queue",
+            Pool::Rayon => "rayon",
+        };
+        write!(f, "{}", name)
     }
 }
 
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
-const DEFAULT_ENGINE: Engine = Engine::kvs;
+const DEFAULT_ENGINE: Engine = Engine::Kvs;
+const DEFAULT_POOL: &str = "naive";
+const DEFAULT_RECOVERY: &str = "truncate";
+const DEFAULT_SYNC: &str = "never";
+const DEFAULT_SYNC_EVERY: &str = "100";
+const DEFAULT_SYNC_INTERVAL: &str = "1000";
+const DEFAULT_COMPACTION_THRESHOLD: &str = "1048576";
+const DEFAULT_COMPACTION_RATIO: &str = "0";
+const DEFAULT_FILE_DEAD_RATIO: &str = "0.5";
 
 
 #[derive(StructOpt, Debug)]
@@ -40,9 +113,90 @@ struct Opt {
         long,
         help = "Sets the storage engine",
         value_name = "ENGINE-NAME",
-        raw(possible_values = "&Engine::variants()")
+        raw(possible_values = "Engine::NAMES")
     )]
     engine: Option<Engine>,
+    #[structopt(
+        long,
+        help = "Sets the thread pool serving connections",
+        value_name = "POOL-NAME",
+        raw(possible_values = "POOL_NAMES"),
+        raw(default_value = "DEFAULT_POOL"),
+        parse(try_from_str)
+    )]
+    pool: Pool,
+    #[structopt(
+        long,
+        help = "Sets the number of worker threads [default: number of CPUs]",
+        value_name = "N"
+    )]
+    threads: Option<u32>,
+    #[structopt(
+        long,
+        help = "Sets how damaged log records of the kvs engine are handled on startup",
+        value_name = "MODE",
+        raw(possible_values = "&Recovery::variants()"),
+        raw(default_value = "DEFAULT_RECOVERY")
+    )]
+    recovery: Recovery,
+    #[structopt(
+        long = "compaction-threshold",
+        help = "Sets how many bytes of stale data trigger a compaction of the kvs engine",
+        value_name = "BYTES",
+        raw(default_value = "DEFAULT_COMPACTION_THRESHOLD")
+    )]
+    compaction_threshold: u64,
+    #[structopt(
+        long = "compaction-ratio",
+        help = "Sets the share of stale data, between 0 and 1, also needed to trigger a compaction of the kvs engine",
+        value_name = "RATIO",
+        raw(default_value = "DEFAULT_COMPACTION_RATIO")
+    )]
+    compaction_ratio: f64,
+    #[structopt(
+        long = "file-dead-ratio",
+        help = "Sets the share of stale data, between 0 and 1, above which a compaction of the kvs engine rewrites a log file",
+        value_name = "RATIO",
+        raw(default_value = "DEFAULT_FILE_DEAD_RATIO")
+    )]
+    file_dead_ratio: f64,
+    #[structopt(
+        long = "max-file-size",
+        help = "Sets the size above which the kvs engine starts a new log file [default: unbounded]",
+        value_name = "BYTES"
+    )]
+    max_file_size: Option<u64>,
+    #[structopt(
+        long,
+        help = "Sets when the kvs engine forces written data to disk",
+        value_name = "POLICY",
+        raw(possible_values = "&SyncMode::variants()"),
+        raw(default_value = "DEFAULT_SYNC")
+    )]
+    sync: SyncMode,
+    #[structopt(
+        long = "sync-every",
+        help = "Sets the number of writes between syncs with --sync every",
+        value_name = "N",
+        raw(default_value = "DEFAULT_SYNC_EVERY")
+    )]
+    sync_every: u64,
+    #[structopt(
+        long = "sync-interval",
+        help = "Sets the milliseconds between syncs with --sync interval",
+        value_name = "MS",
+        raw(default_value = "DEFAULT_SYNC_INTERVAL")
+    )]
+    sync_interval: u64,
+    #[structopt(long = "read-only", help = "Serves the kvs engine without writing to it")]
+    read_only: bool,
+    #[structopt(
+        long = "checkpoint-dir",
+        help = "Sets the directory the checkpoints requested by clients are written under [default: checkpoints are refused]",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    checkpoint_dir: Option<PathBuf>,
 }
 
 
@@ -72,27 +226,106 @@ fn main() {
 fn run(opt : Opt) -> Result<()> {
     let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
+    let threads = opt.threads.unwrap_or(num_cpus::get() as u32);
+    if threads == 0 {
+        return Err(KvsError::StringError(
+            "The number of threads must be greater than zero".to_owned(),
+        ));
+    }
     info!("Storage engine: {}", engine);
+    info!("Thread pool: {} with {} threads", opt.pool, threads);
     info!("Listening on {}", opt.addr);
 
-    // write engine to engine file
-    fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
-
-    let pool = NaiveThreadPool::new(num_cpus::get() as u32)?;
+    if opt.read_only {
+        if engine != Engine::Kvs {
+            return Err(KvsError::StringError(format!(
+                "The {} engine cannot be opened read-only",
+                engine
+            )));
+        }
+        info!("Read-only mode");
+    }
 
     match engine {
-        Engine::kvs => run_with(KvStore::open(&current_dir()?)?, pool, opt.addr),
-        Engine::sled => run_with(
-            SledKvsEngine::new(sled::open(current_dir()?)?), 
-            pool,
-            opt.addr),
+        Engine::Kvs => {
+            let mut options = KvStoreOptions::new()
+                .compaction_threshold(opt.compaction_threshold)
+                .compaction_ratio(opt.compaction_ratio)
+                .file_dead_ratio(opt.file_dead_ratio)
+                .sync_policy(sync_policy(&opt))
+                .read_only(opt.read_only)
+                .recovery_mode(opt.recovery.into());
+            if let Some(max_file_size) = opt.max_file_size {
+                options = options.max_file_size(max_file_size);
+            }
+            // the store locks the directory before the engine file is written
+            let store = KvStore::open_with_options(current_dir()?, &options)?;
+            if !opt.read_only {
+                write_engine_file(&current_dir()?, engine)?;
+            }
+            run_with_pool(store, opt.pool, threads, opt.addr, opt.checkpoint_dir)
+        }
+        Engine::Sled => {
+            // held while serving, so that no kvs store opens the directory meanwhile
+            let _lock = DirLock::acquire(&current_dir()?)?;
+            write_engine_file(&current_dir()?, engine)?;
+            run_with_pool(
+                SledKvsEngine::new(sled::open(current_dir()?)?)?,
+                opt.pool,
+                threads,
+                opt.addr,
+                opt.checkpoint_dir)
+        }
+    }
+}
+
+
+fn sync_policy(opt: &Opt) -> SyncPolicy {
+    match opt.sync {
+        SyncMode::never => SyncPolicy::Never,
+        SyncMode::always => SyncPolicy::Always,
+        SyncMode::every => SyncPolicy::EveryN(opt.sync_every),
+        SyncMode::interval => SyncPolicy::Interval(Duration::from_millis(opt.sync_interval)),
     }
 }
 
 
-pub fn run_with<E: KvsEngine, P: ThreadPool>(engine : E, pool : P, addr: SocketAddr) -> Result<()> {
-    let server = KvsServer::new(engine, pool);
-    server.run(addr)
+fn run_with_pool<E: KvsEngine>(
+    engine: E,
+    pool: Pool,
+    threads: u32,
+    addr: SocketAddr,
+    checkpoint_dir: Option<PathBuf>,
+) -> Result<()> {
+    match pool {
+        Pool::Naive => run_with(engine, NaiveThreadPool::new(threads)?, addr, checkpoint_dir),
+        Pool::SharedQueue => {
+            run_with(engine, SharedQueueThreadPool::new(threads)?, addr, checkpoint_dir)
+        }
+        Pool::Rayon => run_with(engine, RayonThreadPool::new(threads)?, addr, checkpoint_dir),
+    }
+}
+
+
+pub fn run_with<E: KvsEngine, P: ThreadPool>(
+    engine : E,
+    pool : P,
+    addr: SocketAddr,
+    checkpoint_dir: Option<PathBuf>,
+) -> Result<()> {
+    let mut server = KvsServer::new(engine, pool);
+    if let Some(dir) = checkpoint_dir {
+        fs::create_dir_all(&dir)?;
+        info!("Checkpoints written under {}", dir.display());
+        server = server.with_checkpoint_dir(dir);
+    }
+    let handle = server.shutdown_handle();
+    // handles both SIGINT and SIGTERM
+    ctrlc::set_handler(move || handle.shutdown())
+        .map_err(|e| KvsError::StringError(format!("{}", e)))?;
+    server.run(addr)?;
+    info!("kvs-server stopped");
+    Ok(())
 }
 
 
@@ -1,14 +1,19 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
-use crate::{KvsError, Result};
-use serde::Deserialize;
-use serde_json::de::{Deserializer, IoRead};
+use crate::common::{
+    read_message, BatchResponse, CasResponse, CheckpointResponse, GetResponse, RemoveResponse, Request, ScanResponse, SetResponse,
+    TransactionResponse,
+};
+use crate::engines::MAX_TRANSACTION_ATTEMPTS;
+use crate::{KvsError, Result, ScanOptions, WriteBatch};
+use serde::de::DeserializeOwned;
 use std::io::{BufReader, BufWriter, Write};
 use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::Duration;
 
 
 
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
+    reader: BufReader<TcpStream>,
     writer: BufWriter<TcpStream>,
 }
 
@@ -18,40 +23,212 @@ impl KvsClient {
         let tcp_reader = TcpStream::connect(addr)?;
         let tcp_writer = tcp_reader.try_clone()?;
         Ok(KvsClient{
-            reader : Deserializer::from_reader(BufReader::new(tcp_reader)),
+            reader : BufReader::new(tcp_reader),
             writer : BufWriter::new(tcp_writer),
         })
     }
 
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
-        let resp = GetResponse::deserialize(&mut self.reader)?;
+    /// Get the value of a key in the server.
+    pub fn get<K: Into<Vec<u8>>>(&mut self, key: K) -> Result<Option<Vec<u8>>> {
+        let resp = self.request(&Request::Get { key: key.into() })?;
         match resp {
             GetResponse::Ok(value) => Ok(value),
             GetResponse::Err(msg) => Err(KvsError::StringError(msg)),
         }
     }
 
-    /// Set the value of a string key in the server.
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
-        let resp = SetResponse::deserialize(&mut self.reader)?;
+    /// Get the value of a key in the server as a string.
+    pub fn get_string<K: Into<Vec<u8>>>(&mut self, key: K) -> Result<Option<String>> {
+        Ok(self.get(key)?.map(String::from_utf8).transpose()?)
+    }
+
+    /// Set the value of a key in the server.
+    pub fn set<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(&mut self, key: K, value: V) -> Result<()> {
+        let resp = self.request(&Request::Set {
+            key: key.into(),
+            value: value.into(),
+        })?;
         match resp {
             SetResponse::Ok(_) => Ok(()),
             SetResponse::Err(msg) => Err(KvsError::StringError(msg)),
         }
     }
 
-    /// Remove a string key in the server.
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
-        self.writer.flush()?;
-        let resp = RemoveResponse::deserialize(&mut self.reader)?;
+    /// Set the value of a key in the server, for the given duration only.
+    pub fn set_with_ttl<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(
+        &mut self,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> Result<()> {
+        let resp = self.request(&Request::SetWithTtl {
+            key: key.into(),
+            value: value.into(),
+            ttl,
+        })?;
+        match resp {
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Remove a key in the server.
+    pub fn remove<K: Into<Vec<u8>>>(&mut self, key: K) -> Result<()> {
+        let resp = self.request(&Request::Remove { key: key.into() })?;
         match resp {
             RemoveResponse::Ok(_) => Ok(()),
             RemoveResponse::Err(msg) => Err(KvsError::StringError(msg)),
         }
     }
-}
\ No newline at end of file
+
+    /// Set the value of a key to `new`, or remove it if `new` is `None`, in the server
+    /// only if its current value is `expected`. Returns whether the write was made.
+    pub fn compare_and_swap<K: Into<Vec<u8>>>(
+        &mut self,
+        key: K,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.cas_request(&Request::CompareAndSwap {
+            key: key.into(),
+            expected,
+            new,
+        })
+    }
+
+    /// Set the value of a key in the server only if the key does not exist.
+    /// Returns whether the value was set.
+    pub fn set_if_absent<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(&mut self, key: K, value: V) -> Result<bool> {
+        self.cas_request(&Request::SetIfAbsent {
+            key: key.into(),
+            value: value.into(),
+        })
+    }
+
+    fn cas_request(&mut self, req: &Request) -> Result<bool> {
+        match self.request(req)? {
+            CasResponse::Ok(swapped) => Ok(swapped),
+            CasResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Apply every write of a batch atomically in the server.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let resp = self.request(&Request::Batch { batch })?;
+        match resp {
+            BatchResponse::Ok(_) => Ok(()),
+            BatchResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Get the key/value pairs with keys from `start` (inclusive) to `end` (exclusive)
+    /// in the server. A missing bound leaves that side of the range open.
+    pub fn scan(
+        &mut self,
+        start: Option<Vec<u8>>,
+        end: Option<Vec<u8>>,
+        options: ScanOptions,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_request(&Request::Scan { start, end, options })
+    }
+
+    /// Get the key/value pairs with keys starting with `prefix` in the server.
+    pub fn scan_prefix<P: Into<Vec<u8>>>(
+        &mut self,
+        prefix: P,
+        options: ScanOptions,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_request(&Request::ScanPrefix {
+            prefix: prefix.into(),
+            options,
+        })
+    }
+
+    fn scan_request(&mut self, req: &Request) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = Vec::new();
+        // the pairs come in chunks until `Done`
+        let mut resp = self.request(req)?;
+        loop {
+            match resp {
+                ScanResponse::Pairs(chunk) => pairs.extend(chunk),
+                ScanResponse::Done => return Ok(pairs),
+                ScanResponse::Err(msg) => return Err(KvsError::StringError(msg)),
+            }
+            resp = read_message(&mut self.reader)?;
+        }
+    }
+
+    /// Write a checkpoint of the server's data to the new directory `dest`, a path
+    /// relative to the checkpoint directory of the server, see
+    /// `KvsServer::with_checkpoint_dir` and `KvsEngine::checkpoint`.
+    pub fn checkpoint<P: Into<PathBuf>>(&mut self, dest: P) -> Result<()> {
+        match self.request(&Request::Checkpoint { dest: dest.into() })? {
+            CheckpointResponse::Ok(_) => Ok(()),
+            CheckpointResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Begin a transaction in the server.
+    ///
+    /// Until `commit` or `rollback`, `get` reads the data as it was when the
+    /// transaction began, along with its own writes, and `set` and `remove` are
+    /// only applied on commit. Other requests fail.
+    pub fn begin(&mut self) -> Result<()> {
+        self.transaction_request(&Request::Begin)
+    }
+
+    /// Commit the transaction begun.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Conflict`, and nothing is written, if a key read or
+    /// written by the transaction was changed by another client since it began.
+    pub fn commit(&mut self) -> Result<()> {
+        self.transaction_request(&Request::Commit)
+    }
+
+    /// Discard the transaction begun and its writes.
+    pub fn rollback(&mut self) -> Result<()> {
+        self.transaction_request(&Request::Rollback)
+    }
+
+    /// Run `f` in a transaction, which is begun before and committed after.
+    ///
+    /// `f` is run again if the commit conflicts, up to 100 times before failing with
+    /// `KvsError::Conflict`, and the transaction is rolled back if it fails.
+    pub fn transaction<F, T>(&mut self, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut KvsClient) -> Result<T>,
+    {
+        for _ in 0..MAX_TRANSACTION_ATTEMPTS {
+            self.begin()?;
+            let res = match f(self) {
+                Ok(res) => res,
+                Err(e) => {
+                    self.rollback()?;
+                    return Err(e);
+                }
+            };
+            match self.commit() {
+                Err(KvsError::Conflict) => continue,
+                committed => return committed.map(|_| res),
+            }
+        }
+        Err(KvsError::Conflict)
+    }
+
+    fn transaction_request(&mut self, req: &Request) -> Result<()> {
+        match self.request(req)? {
+            TransactionResponse::Ok(_) => Ok(()),
+            TransactionResponse::Conflict => Err(KvsError::Conflict),
+            TransactionResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Sends a request and reads its response.
+    fn request<T: DeserializeOwned>(&mut self, req: &Request) -> Result<T> {
+        bincode::serialize_into(&mut self.writer, req)?;
+        self.writer.flush()?;
+        read_message(&mut self.reader)
+    }
+}
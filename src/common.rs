@@ -1,16 +1,66 @@
+use crate::{Result, ScanOptions, WriteBatch};
+use bincode::Options;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::Duration;
 
 
+/// The largest request or response read from a connection, in bytes.
+pub(crate) const MAX_MESSAGE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The size in bytes over which the pairs found by a scan are sent in another response.
+pub(crate) const SCAN_CHUNK_SIZE: usize = 1024 * 1024;
+
+
+/// Reads a request or a response, failing instead of allocating more than `MAX_MESSAGE_SIZE`.
+pub(crate) fn read_message<T: DeserializeOwned, R: Read>(reader: R) -> Result<T> {
+    // the same encoding as `bincode::serialize_into`, which writes the messages
+    let options = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_MESSAGE_SIZE);
+    Ok(options.deserialize_from(reader)?)
+}
+
+
+/// Requests and responses are bincode-serialized back to back on the connection.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get { key: Vec<u8> },
+    Set { key: Vec<u8>, value: Vec<u8> },
+    SetWithTtl {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    },
+    Remove { key: Vec<u8> },
+    Scan {
+        start: Option<Vec<u8>>,
+        end: Option<Vec<u8>>,
+        options: ScanOptions,
+    },
+    ScanPrefix { prefix: Vec<u8>, options: ScanOptions },
+    Batch { batch: WriteBatch },
+    CompareAndSwap {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    SetIfAbsent { key: Vec<u8>, value: Vec<u8> },
+    /// Starts a transaction: the `Get`, `Set` and `Remove` requests that follow
+    /// read from a snapshot and buffer their writes until `Commit`.
+    Begin,
+    Commit,
+    Rollback,
+    /// Writes a checkpoint of the engine to `dest`, a path on the server.
+    Checkpoint { dest: PathBuf },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GetResponse {
-    Ok(Option<String>),
+    Ok(Option<Vec<u8>>),
     Err(String),
 }
 
@@ -24,4 +74,39 @@ pub enum SetResponse {
 pub enum RemoveResponse {
     Ok(()),
     Err(String),
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CasResponse {
+    Ok(bool),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum BatchResponse {
+    Ok(()),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TransactionResponse {
+    Ok(()),
+    /// The transaction was not committed, a key it used was written meanwhile.
+    Conflict,
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CheckpointResponse {
+    Ok(()),
+    Err(String),
+}
+
+/// The pairs found by a scan are sent in chunks of about `SCAN_CHUNK_SIZE` bytes,
+/// each in a `Pairs` response, followed by `Done`, or `Err` if the scan fails.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ScanResponse {
+    Pairs(Vec<(Vec<u8>, Vec<u8>)>),
+    Done,
+    Err(String),
+}
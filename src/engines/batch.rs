@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+
+
+/// A group of writes applied atomically by `KvsEngine::write_batch`.
+///
+/// # Example
+///
+/// ```no_run
+/// # use kvs::{KvStore, KvsEngine, WriteBatch};
+/// let store = KvStore::open("data")?;
+/// let mut batch = WriteBatch::new();
+/// batch.set("account/alice", "90");
+/// batch.set("account/bob", "110");
+/// batch.remove("transfer/42");
+/// store.write_batch(batch)?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+
+/// A single write of a `WriteBatch`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum BatchOp {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+}
+
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Sets the value of a key when the batch is written.
+    pub fn set<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(&mut self, key: K, value: V) {
+        self.ops.push(BatchOp::Set {
+            key: key.into(),
+            value: value.into(),
+        });
+    }
+
+    /// Removes a key when the batch is written.
+    ///
+    /// Unlike `KvsEngine::remove`, removing a missing key is not an error.
+    pub fn remove<K: Into<Vec<u8>>>(&mut self, key: K) {
+        self.ops.push(BatchOp::Remove { key: key.into() });
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns true if the batch contains no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Removes every write from the batch.
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+}
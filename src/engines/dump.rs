@@ -0,0 +1,128 @@
+//! Dumps, an engine-neutral copy of the key/value pairs of an engine.
+//!
+//! A dump starts with `DUMP_MAGIC` and the format version, followed by one
+//! record per pair in the framing of the `kvs` log files, and ends with a
+//! record holding the number of pairs, so that a truncated dump is told from
+//! a complete one. Each pair carries the expiry time of its key, so that keys
+//! set with a TTL expire at the same time once restored.
+
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::kvs::log_format;
+use super::ttl::now_millis;
+use super::{KvsEngine, KvsSnapshot, WriteBatch};
+use crate::{KvsError, Result};
+
+const DUMP_MAGIC: [u8; 4] = *b"KVSD";
+const DUMP_VERSION: u32 = 1;
+// number of pairs restored per write batch
+const RESTORE_BATCH_LEN: usize = 1000;
+
+
+#[derive(Serialize, Deserialize)]
+enum DumpRecord {
+    Pair {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        // in milliseconds since the Unix epoch
+        expires_at: Option<u64>,
+    },
+    End { count: u64 },
+}
+
+
+/// Writes every key/value pair of `engine` to `writer`, in key order.
+///
+/// The pairs are read from a snapshot as they are written, so the dump is
+/// consistent even while the engine is written to, without holding every
+/// pair in memory. Expired keys are left out. Returns the number of pairs written.
+///
+/// # Example
+///
+/// ```no_run
+/// # use std::fs::File;
+/// # use kvs::{KvStore, SledKvsEngine};
+/// let store = KvStore::open("kvs-data")?;
+/// kvs::dump(&store, File::create("data.dump")?)?;
+/// let engine = SledKvsEngine::new(sled::open("sled-data")?)?;
+/// kvs::restore(&engine, File::open("data.dump")?)?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+pub fn dump<E: KvsEngine, W: Write>(engine: &E, writer: W) -> Result<u64> {
+    let mut writer = BufWriter::new(writer);
+    writer.write_all(&DUMP_MAGIC)?;
+    writer.write_all(&DUMP_VERSION.to_le_bytes())?;
+    let snapshot = engine.snapshot()?;
+    let mut count = 0;
+    for entry in snapshot.entries()? {
+        let (key, value, expires_at) = entry?;
+        log_format::write_record(&mut writer, &DumpRecord::Pair { key, value, expires_at })?;
+        count += 1;
+    }
+    log_format::write_record(&mut writer, &DumpRecord::End { count })?;
+    writer.flush()?;
+    Ok(count)
+}
+
+
+/// Writes the key/value pairs of the dump read from `reader` into `engine`.
+///
+/// Existing keys are overwritten, the others left as they are. Keys keep the
+/// expiry time they had in the dump, those expired since are skipped. The
+/// pairs are written in batches, so a dump found damaged or truncated midway
+/// may be partially restored. Returns the number of pairs restored.
+///
+/// # Errors
+///
+/// It returns `KvsError::UnsupportedLogFormat` if `reader` is not a dump, and
+/// `KvsError::Corruption` if the dump is damaged or truncated.
+pub fn restore<E: KvsEngine, R: Read>(engine: &E, reader: R) -> Result<u64> {
+    let mut reader = BufReader::new(reader);
+    let mut header = [0; 8];
+    reader.read_exact(&mut header)?;
+    if header[..4] != DUMP_MAGIC {
+        return Err(KvsError::UnsupportedLogFormat("not a kvs dump".to_owned()));
+    }
+    if header[4..] != DUMP_VERSION.to_le_bytes() {
+        return Err(KvsError::UnsupportedLogFormat(
+            "unknown dump version".to_owned(),
+        ));
+    }
+    // pairs read, to check against the count of the dump
+    let mut count = 0;
+    let mut restored = 0;
+    let mut batch = WriteBatch::new();
+    loop {
+        match log_format::read_record(&mut reader)? {
+            Some(DumpRecord::Pair { key, value, expires_at: None }) => {
+                batch.set(key, value);
+                count += 1;
+                restored += 1;
+                if batch.len() == RESTORE_BATCH_LEN {
+                    engine.write_batch(batch)?;
+                    batch = WriteBatch::new();
+                }
+            }
+            // write batches carry no expiry time, so these are set one by one
+            Some(DumpRecord::Pair { key, value, expires_at: Some(expires_at) }) => {
+                count += 1;
+                let now = now_millis();
+                if expires_at > now {
+                    engine.set_with_ttl(key, value, Duration::from_millis(expires_at - now))?;
+                    restored += 1;
+                }
+            }
+            Some(DumpRecord::End { count: dumped }) if dumped == count => break,
+            Some(DumpRecord::End { .. }) => {
+                return Err(KvsError::Corruption("dump pair count mismatch".to_owned()))
+            }
+            None => return Err(KvsError::Corruption("truncated dump".to_owned())),
+        }
+    }
+    engine.write_batch(batch)?;
+    engine.flush()?;
+    Ok(restored)
+}
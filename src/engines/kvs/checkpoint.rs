@@ -0,0 +1,60 @@
+//! Checkpoints, copies of a store's directory taken while the store is in use.
+//!
+//! A checkpoint holds the log files the store reads from when it is taken, so
+//! `KvStore::open` loads it like any other directory. Sealed log files are
+//! never written again, so they are hard-linked along with their hint files,
+//! and only copied if linking fails, e.g. across file systems. The active log
+//! file is copied up to the end of the last write. The `engine` file is
+//! written along, so that `kvs-server` opens the checkpoint with this engine.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+use super::{hint, log_path};
+use crate::engines::{write_engine_file, Engine};
+use crate::Result;
+
+
+/// Writes a checkpoint of the store in `src` to the new directory `dest`.
+///
+/// `sealed` are the generations to link, `active` the generation of the active
+/// log file and its length, if any. The files of `sealed` are copied instead if
+/// `link` is false.
+pub(super) fn write(
+    src: &Path,
+    dest: &Path,
+    sealed: &[u64],
+    active: Option<(u64, u64)>,
+    link: bool,
+) -> Result<()> {
+    fs::create_dir(dest)?;
+    for &gen in sealed {
+        link_or_copy(&log_path(src, gen), &log_path(dest, gen), link)?;
+        let hint_path = hint::hint_path(src, gen);
+        match link_or_copy(&hint_path, &hint::hint_path(dest, gen), link) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            res => res?,
+        }
+    }
+    if let Some((gen, len)) = active {
+        let mut reader = File::open(log_path(src, gen))?.take(len);
+        let mut writer = BufWriter::new(File::create(log_path(dest, gen))?);
+        io::copy(&mut reader, &mut writer)?;
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+    }
+    write_engine_file(dest, Engine::Kvs)?;
+    // makes the new directory entries durable
+    File::open(dest)?.sync_all()?;
+    Ok(())
+}
+
+
+fn link_or_copy(src: &Path, dest: &Path, link: bool) -> io::Result<()> {
+    if link && fs::hard_link(src, dest).is_ok() {
+        return Ok(());
+    }
+    fs::copy(src, dest)?;
+    File::open(dest)?.sync_data()
+}
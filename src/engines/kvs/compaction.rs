@@ -0,0 +1,600 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::ops::{Bound, Range};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+
+use crossbeam::channel::{self, Receiver, Sender};
+use log::{debug, error, warn};
+
+use super::hint::{self, HintEntry};
+use super::snapshot::Snapshots;
+use super::{
+    decode_command, log_format, log_path, new_log_file, sorted_gen_list, BufWriterWithPos, Command,
+    CommandPos, Index, KvStoreWriter, LogReader, PendingBatch,
+};
+use crate::engines::ttl::now_millis;
+use crate::{KvsError, Result};
+
+
+/// Size and garbage of every log file, which tell when to compact and which files.
+#[derive(Default)]
+pub(super) struct LogStats {
+    files: BTreeMap<u64, FileStats>,
+    total_size: u64,
+    garbage: u64,
+    // bytes of the live values of every file by expiry time, the expired values
+    // kept by compactions included
+    expiring: BTreeMap<u64, u64>,
+    // whether garbage was added or files changed since the last `select`
+    changed: bool,
+    // time of the last `select`, in milliseconds since the Unix epoch
+    selected_at: u64,
+}
+
+
+#[derive(Default)]
+struct FileStats {
+    size: u64,
+    // bytes of the commands that are stale or will be once compacted
+    garbage: u64,
+    // bytes of the live values by expiry time, garbage once expired
+    expiring: BTreeMap<u64, u64>,
+    // written in an older format version
+    outdated: bool,
+}
+
+
+impl FileStats {
+    fn expired(&self, now: u64) -> u64 {
+        self.expiring.range(..=now).map(|(_, bytes)| bytes).sum()
+    }
+}
+
+
+impl LogStats {
+    /// Records a log file of `size` bytes, written in the format `version`.
+    pub(super) fn add_file(&mut self, gen: u64, size: u64, version: u32) {
+        let file = self.files.entry(gen).or_default();
+        file.size += size;
+        file.outdated = version < log_format::LOG_VERSION;
+        self.total_size += size;
+        self.changed = true;
+    }
+
+    /// Records `bytes` appended to the log file of `gen`.
+    pub(super) fn grow(&mut self, gen: u64, bytes: u64) {
+        self.files.entry(gen).or_default().size += bytes;
+        self.total_size += bytes;
+    }
+
+    /// Records that the value at `cmd_pos` is live, if it expires.
+    pub(super) fn add_live(&mut self, cmd_pos: &CommandPos) {
+        if let Some(expires_at) = cmd_pos.expires_at {
+            let file = self.files.entry(cmd_pos.gen).or_default();
+            *file.expiring.entry(expires_at).or_default() += cmd_pos.len;
+            *self.expiring.entry(expires_at).or_default() += cmd_pos.len;
+        }
+    }
+
+    /// Records that the value at `cmd_pos`, expired already, was kept by a compaction.
+    ///
+    /// Its file does not count it as garbage, but the sweep still has to remove it.
+    pub(super) fn add_expired(&mut self, cmd_pos: &CommandPos) {
+        if let Some(expires_at) = cmd_pos.expires_at {
+            *self.expiring.entry(expires_at).or_default() += cmd_pos.len;
+        }
+    }
+
+    /// Records that the command at `cmd_pos` is garbage.
+    pub(super) fn add_garbage(&mut self, cmd_pos: &CommandPos) {
+        let file = self.files.entry(cmd_pos.gen).or_default();
+        file.garbage += cmd_pos.len;
+        self.garbage += cmd_pos.len;
+        self.changed = true;
+        if let Some(expires_at) = cmd_pos.expires_at {
+            // it is no longer live, whether it expired or not
+            subtract(&mut file.expiring, expires_at, cmd_pos.len);
+            subtract(&mut self.expiring, expires_at, cmd_pos.len);
+        }
+    }
+
+    /// Forgets a log file removed by a compaction.
+    pub(super) fn remove_file(&mut self, gen: u64) {
+        if let Some(file) = self.files.remove(&gen) {
+            self.total_size -= file.size;
+            self.garbage -= file.garbage;
+            for (expires_at, bytes) in file.expiring {
+                subtract(&mut self.expiring, expires_at, bytes);
+            }
+            self.changed = true;
+        }
+    }
+
+    /// Returns the generations of the log files, in ascending order.
+    pub(super) fn gens(&self) -> impl Iterator<Item = u64> + '_ {
+        self.files.keys().copied()
+    }
+
+    pub(super) fn total_size(&self) -> u64 {
+        self.total_size
+    }
+
+    pub(super) fn garbage(&self) -> u64 {
+        self.garbage
+    }
+
+    /// Returns true if a live value has expired by `now`, its removal not being
+    /// written yet.
+    pub(super) fn has_expired(&self, now: u64) -> bool {
+        self.expiring.range(..=now).next().is_some()
+    }
+
+    /// Returns true if `select` may return other generations than it did last
+    /// time: garbage was added, files changed or values expired since.
+    pub(super) fn changed(&self, now: u64) -> bool {
+        // the clock may not have moved since, or even gone back
+        if self.changed || now <= self.selected_at {
+            return self.changed;
+        }
+        let since = (Bound::Excluded(self.selected_at), Bound::Included(now));
+        self.expiring.range(since).next().is_some()
+    }
+
+    /// Returns the generations worth compacting: those whose share of garbage,
+    /// expired values included, exceeds `dead_ratio`, and those written in an
+    /// older format version.
+    pub(super) fn select(&mut self, dead_ratio: f64, now: u64) -> Vec<u64> {
+        self.changed = false;
+        self.selected_at = now;
+        self.files
+            .iter()
+            .filter(|(_, file)| {
+                let dead = file.garbage + file.expired(now);
+                file.outdated || dead as f64 > dead_ratio * file.size as f64
+            })
+            .map(|(&gen, _)| gen)
+            .collect()
+    }
+
+    /// Returns the bytes of the generations `gens` that are not garbage.
+    pub(super) fn live_size(&self, gens: &[u64], now: u64) -> u64 {
+        gens.iter()
+            .filter_map(|gen| self.files.get(gen))
+            .map(|file| file.size.saturating_sub(file.garbage + file.expired(now)))
+            .sum()
+    }
+}
+
+
+/// Subtracts `bytes` from the entry of `expires_at`, dropping it once empty.
+fn subtract(expiring: &mut BTreeMap<u64, u64>, expires_at: u64, bytes: u64) {
+    if let Some(total) = expiring.get_mut(&expires_at) {
+        *total = total.saturating_sub(bytes);
+        if *total == 0 {
+            expiring.remove(&expires_at);
+        }
+    }
+}
+
+
+/// A compaction handed over to the compaction thread.
+struct CompactionJob {
+    // generations to compact, in ascending order
+    gens: Vec<u64>,
+    // generations the commands still needed are copied to
+    output_gens: Range<u64>,
+    // size above which the copy moves on to the next generation
+    max_file_size: Option<u64>,
+}
+
+
+/// Handle to the background thread compacting the log.
+///
+/// The thread only holds a weak reference to the writer, so it stops once
+/// every `KvStore` is dropped.
+pub(super) struct Compactor {
+    tx: Option<Sender<CompactionJob>>,
+    handle: Option<JoinHandle<()>>,
+    running: Arc<AtomicBool>,
+}
+
+
+impl Compactor {
+    pub(super) fn spawn(
+        writer: Weak<Mutex<KvStoreWriter>>,
+        epoch: Arc<AtomicU64>,
+        index: Arc<Index>,
+        snapshots: Arc<Snapshots>,
+        path: Arc<PathBuf>,
+    ) -> Result<Compactor> {
+        let (tx, rx) = channel::unbounded();
+        let running = Arc::new(AtomicBool::new(false));
+        let worker = CompactionWorker {
+            writer,
+            epoch,
+            index,
+            snapshots,
+            path,
+            running: Arc::clone(&running),
+        };
+        let handle = thread::Builder::new()
+            .name("kvs-compaction".to_owned())
+            .spawn(move || worker.run(rx))?;
+        Ok(Compactor {
+            tx: Some(tx),
+            handle: Some(handle),
+            running,
+        })
+    }
+
+    /// Returns true while a compaction is in progress.
+    pub(super) fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Starts compacting the generations `gens` into `output_gens`, filling each
+    /// output generation up to `max_file_size` before moving on to the next one.
+    pub(super) fn start(&self, gens: Vec<u64>, output_gens: Range<u64>, max_file_size: Option<u64>) {
+        self.running.store(true, Ordering::SeqCst);
+        if let Some(tx) = &self.tx {
+            let job = CompactionJob {
+                gens,
+                output_gens,
+                max_file_size,
+            };
+            if tx.send(job).is_err() {
+                error!("The compaction thread has exited");
+                self.running.store(false, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        // closing the channel makes the thread exit once the current job is done
+        self.tx.take();
+        if let Some(handle) = self.handle.take() {
+            // the writer may be dropped by the compaction thread itself
+            if handle.thread().id() != thread::current().id() && handle.join().is_err() {
+                error!("The compaction thread panicked");
+            }
+        }
+    }
+}
+
+
+struct CompactionWorker {
+    writer: Weak<Mutex<KvStoreWriter>>,
+    // number of compactions finished, see `KvStoreReader::close_stale_handles`
+    epoch: Arc<AtomicU64>,
+    index: Arc<Index>,
+    snapshots: Arc<Snapshots>,
+    path: Arc<PathBuf>,
+    running: Arc<AtomicBool>,
+}
+
+
+impl CompactionWorker {
+    fn run(self, rx: Receiver<CompactionJob>) {
+        for job in rx {
+            let output_gens = job.output_gens.clone();
+            if let Err(e) = self.compact(&job.gens, job.output_gens, job.max_file_size) {
+                error!("Compaction into generations {:?} failed: {}", output_gens, e);
+                self.remove_gens(output_gens);
+            }
+            self.running.store(false, Ordering::SeqCst);
+        }
+        debug!("Compaction thread exits because the store is dropped.");
+    }
+
+    /// Rewrites the generations `gens` into `output_gens`, keeping only the
+    /// commands still needed, and removes them.
+    ///
+    /// The log files are read without holding the writer lock, so writes continue
+    /// to the newer generation in the meantime. A value is copied if the index
+    /// still points to it, and the index is only switched to the copies under the
+    /// lock, for the entries that were not overwritten meanwhile.
+    ///
+    /// A removal is kept as long as an older generation, left untouched, may hold
+    /// the key it removes. So is an expired value, which is dropped from the index
+    /// otherwise. Commands are decoded and written again, so that files of an older
+    /// format version are rewritten in the current one. A hint file is written for
+    /// each new generation.
+    fn compact(&self, gens: &[u64], output_gens: Range<u64>, max_file_size: Option<u64>) -> Result<()> {
+        let oldest_kept = sorted_gen_list(&self.path)?
+            .into_iter()
+            .find(|gen| !gens.contains(gen));
+        let mut output = CompactionOutput::new(&self.path, gens, output_gens.clone(), max_file_size)?;
+        let now = now_millis();
+        for &gen in gens {
+            let older_kept = oldest_kept.is_some_and(|kept| kept < gen);
+            self.copy_gen(gen, older_kept, now, &mut output)?;
+        }
+        output.seal()?;
+        let CompactionOutput {
+            sizes,
+            moved,
+            expired,
+            ..
+        } = output;
+
+        let deferred;
+        {
+            let writer = match self.writer.upgrade() {
+                Some(writer) => writer,
+                None => {
+                    // the store is dropped, the compacted generations are left as they are
+                    debug!("Compaction abandoned because the store is dropped.");
+                    self.remove_gens(output_gens);
+                    return Ok(());
+                }
+            };
+            let mut writer = writer.lock().unwrap();
+            // the removals kept are not counted as garbage, so that their file is
+            // not compacted again only to keep them, see `load_hint`
+            for (gen, size) in sizes {
+                writer.stats.add_file(gen, size, log_format::LOG_VERSION);
+            }
+            for (key, old_pos, new_pos) in moved {
+                match self.index.get(&key) {
+                    Some(entry) if entry.value().load() == old_pos => {
+                        entry.value().store(new_pos);
+                        // an expired value kept is not counted as garbage either
+                        if new_pos.is_expired(now) {
+                            writer.stats.add_expired(&new_pos);
+                        } else {
+                            writer.stats.add_live(&new_pos);
+                        }
+                    }
+                    // the copy is stale already
+                    _ => writer.stats.add_garbage(&new_pos),
+                }
+            }
+            for (key, old_pos) in expired {
+                // its only record is in a generation about to be removed
+                if self
+                    .index
+                    .get(&key)
+                    .is_some_and(|entry| entry.value().load() == old_pos)
+                {
+                    self.index.remove(&key);
+                }
+            }
+            for &gen in gens {
+                writer.stats.remove_file(gen);
+            }
+            // open snapshots may still read the compacted generations
+            deferred = self.snapshots.defer_removal(gens);
+            self.epoch.fetch_add(1, Ordering::SeqCst);
+        }
+        if !deferred {
+            remove_compacted(&self.path, gens);
+        }
+        Ok(())
+    }
+
+    /// Copies the commands of generation `gen` still needed to the output.
+    ///
+    /// The log file is read the way `KvStore::open` loads it: up to the first
+    /// damaged record, and batches only count if they are complete.
+    fn copy_gen(&self, gen: u64, older_kept: bool, now: u64, output: &mut CompactionOutput) -> Result<()> {
+        let file = match File::open(log_path(&self.path, gen)) {
+            Ok(file) => file,
+            // removed behind the store's back: there is nothing left to copy, and
+            // the generation is dropped from the stats along with the others
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                warn!("The log file of generation {} is missing", gen);
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut log_reader = match LogReader::open(file) {
+            Ok(log_reader) => log_reader,
+            // nothing of it was loaded either
+            Err(KvsError::Corruption(_)) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let version = log_reader.version;
+        let reader = &mut log_reader.reader;
+        let mut pos = reader.pos;
+        let mut batch: Option<PendingBatch> = None;
+        loop {
+            let cmd = match decode_command(reader, version) {
+                Ok(Some(cmd)) => cmd,
+                Ok(None) | Err(KvsError::Corruption(_)) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let cmd_pos: CommandPos = (gen, pos..reader.pos).into();
+            pos = reader.pos;
+            match (&mut batch, cmd) {
+                (Some(_), Command::Batch { .. }) => return Ok(()),
+                (None, Command::Batch { len }) => {
+                    batch = Some(PendingBatch {
+                        start: cmd_pos.pos,
+                        remaining: len,
+                        cmds: Vec::new(),
+                    });
+                }
+                (Some(pending), cmd) => {
+                    pending.remaining -= 1;
+                    pending.cmds.push((cmd, cmd_pos));
+                }
+                (None, cmd) => self.copy_command(cmd, cmd_pos, older_kept, now, output)?,
+            }
+            if batch.as_ref().is_some_and(|pending| pending.remaining == 0) {
+                // the commands of the batch are copied on their own, they are all
+                // known to be on disk
+                for (cmd, cmd_pos) in batch.take().unwrap().cmds {
+                    self.copy_command(cmd, cmd_pos, older_kept, now, output)?;
+                }
+            }
+        }
+    }
+
+    /// Copies a command read at `cmd_pos` to the output if it is still needed.
+    fn copy_command(
+        &self,
+        cmd: Command,
+        cmd_pos: CommandPos,
+        older_kept: bool,
+        now: u64,
+        output: &mut CompactionOutput,
+    ) -> Result<()> {
+        match &cmd {
+            Command::Set { key, .. } => {
+                let old_pos = match self.index.get(key) {
+                    Some(entry) => entry.value().load(),
+                    None => return Ok(()),
+                };
+                if (old_pos.gen, old_pos.pos) != (cmd_pos.gen, cmd_pos.pos) {
+                    return Ok(());
+                }
+                if old_pos.is_expired(now) && !older_kept {
+                    output.expired.push((key.clone(), old_pos));
+                    return Ok(());
+                }
+                let copy_pos = CommandPos {
+                    expires_at: old_pos.expires_at,
+                    seq: old_pos.seq,
+                    ..output.write(&cmd)?
+                };
+                output.hint_entries.push(HintEntry::Set {
+                    key: key.clone(),
+                    cmd_pos: copy_pos,
+                });
+                output.moved.push((key.clone(), old_pos, copy_pos));
+            }
+            Command::Remove { key } => {
+                if older_kept && !self.index.contains_key(key) {
+                    let copy_pos = output.write(&cmd)?;
+                    output.hint_entries.push(HintEntry::Remove {
+                        key: key.clone(),
+                        cmd_pos: copy_pos,
+                    });
+                }
+            }
+            // the header is useless once the batch is read
+            Command::Batch { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// Removes the generations written by a compaction that did not finish.
+    fn remove_gens(&self, gens: Range<u64>) {
+        for gen in gens {
+            let file_path = log_path(&self.path, gen);
+            if !file_path.exists() {
+                continue;
+            }
+            if let Err(e) = hint::remove(&self.path, gen).and_then(|_| fs::remove_file(&file_path)) {
+                error!("{:?} cannot be deleted: {}", file_path, e);
+            }
+        }
+    }
+}
+
+
+/// Removes the log files of the compacted generations, and their hint files.
+///
+/// Errors are only logged: the index no longer refers to these files.
+pub(super) fn remove_compacted(path: &Path, gens: &[u64]) {
+    // Note that actually these files are not deleted immediately because `KvStoreReader`s
+    // still keep open file handles. When `KvStoreReader` is used next time, it will clear
+    // its stale file handles. On Unix, the files will be deleted after all the handles
+    // are closed.
+    for &gen in gens {
+        let file_path = log_path(path, gen);
+        // the hint goes first, it must not outlive its log
+        match hint::remove(path, gen).and_then(|_| fs::remove_file(&file_path)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => error!("{:?} cannot be deleted: {}", file_path, e),
+            Ok(()) => {}
+        }
+    }
+}
+
+
+/// The log files written by a compaction.
+struct CompactionOutput<'a> {
+    path: &'a Path,
+    // generations being compacted, listed in the hint files
+    compacted: &'a [u64],
+    gens: Range<u64>,
+    max_file_size: Option<u64>,
+    // generation being written
+    gen: u64,
+    writer: BufWriterWithPos<File>,
+    // commands written to `gen`, listed in its hint file
+    hint_entries: Vec<HintEntry>,
+    // size of each generation sealed so far
+    sizes: Vec<(u64, u64)>,
+    // key, previous and new position of each value copied
+    moved: Vec<(Vec<u8>, CommandPos, CommandPos)>,
+    // expired values left out, along with their position
+    expired: Vec<(Vec<u8>, CommandPos)>,
+}
+
+
+impl<'a> CompactionOutput<'a> {
+    fn new(
+        path: &'a Path,
+        compacted: &'a [u64],
+        gens: Range<u64>,
+        max_file_size: Option<u64>,
+    ) -> Result<Self> {
+        Ok(CompactionOutput {
+            path,
+            compacted,
+            gen: gens.start,
+            writer: new_log_file(path, gens.start)?,
+            gens,
+            max_file_size,
+            hint_entries: Vec::new(),
+            sizes: Vec::new(),
+            moved: Vec::new(),
+            expired: Vec::new(),
+        })
+    }
+
+    /// Writes a command, moving on to the next generation once the current one
+    /// reaches `max_file_size`, except in the last one, which takes whatever is left.
+    fn write(&mut self, cmd: &Command) -> Result<CommandPos> {
+        let full = self
+            .max_file_size
+            .is_some_and(|max_file_size| self.writer.pos >= max_file_size);
+        if full && self.gen + 1 < self.gens.end {
+            self.seal()?;
+            self.gen += 1;
+            self.writer = new_log_file(self.path, self.gen)?;
+        }
+        let pos = self.writer.pos;
+        log_format::write_record(&mut self.writer, cmd)?;
+        Ok((self.gen, pos..self.writer.pos).into())
+    }
+
+    /// Flushes the current generation to disk and writes its hint file.
+    fn seal(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        let log_len = self.writer.pos;
+        // the log can still be loaded without its hint
+        if let Err(e) = hint::write(
+            self.path,
+            self.gen,
+            log_len,
+            &self.hint_entries,
+            self.compacted,
+        ) {
+            error!("Hint file of generation {} cannot be written: {}", self.gen, e);
+        }
+        self.hint_entries.clear();
+        self.sizes.push((self.gen, log_len));
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender};
+use log::{debug, error};
+
+use super::{Command, CommandPos, Index, KvStoreWriter};
+use crate::engines::ttl::now_millis;
+use crate::Result;
+
+
+/// Handle to the background thread removing expired keys.
+///
+/// Expired keys are hidden from reads as soon as they expire; the sweeper writes
+/// their removal to the log so that they stop taking up memory and disk space.
+pub(super) struct Sweeper {
+    // dropping it stops the thread
+    tx: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+
+impl Sweeper {
+    pub(super) fn spawn(
+        writer: Weak<Mutex<KvStoreWriter>>,
+        index: Arc<Index>,
+        interval: Duration,
+    ) -> Result<Sweeper> {
+        let (tx, rx) = channel::bounded(0);
+        let handle = thread::Builder::new()
+            .name("kvs-sweeper".to_owned())
+            .spawn(move || sweep_periodically(rx, writer, index, interval))?;
+        Ok(Sweeper {
+            tx: Some(tx),
+            handle: Some(handle),
+        })
+    }
+}
+
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(handle) = self.handle.take() {
+            // the writer may be dropped by the sweeper thread itself
+            if handle.thread().id() != thread::current().id() && handle.join().is_err() {
+                error!("The sweeper thread panicked");
+            }
+        }
+    }
+}
+
+
+fn sweep_periodically(
+    rx: Receiver<()>,
+    writer: Weak<Mutex<KvStoreWriter>>,
+    index: Arc<Index>,
+    interval: Duration,
+) {
+    while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => break,
+        };
+        if let Err(e) = sweep(&writer, &index) {
+            error!("Removing expired keys failed: {}", e);
+        }
+    }
+    debug!("Sweeper thread exits because the store is dropped.");
+}
+
+
+/// Writes the removal of every expired key.
+fn sweep(writer: &Mutex<KvStoreWriter>, index: &Index) -> Result<()> {
+    let now = now_millis();
+    // the stats track the expiry times of the live values, so the index is only
+    // walked once one of them has passed
+    if !writer.lock().unwrap().stats.has_expired(now) {
+        return Ok(());
+    }
+    let expired: Vec<(Vec<u8>, CommandPos)> = index
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().load()))
+        .filter(|(_, cmd_pos)| cmd_pos.is_expired(now))
+        .collect();
+    if expired.is_empty() {
+        return Ok(());
+    }
+
+    let mut writer = writer.lock().unwrap();
+    writer.apply_pending()?;
+    // skips the keys written again since they were collected
+    let cmds: Vec<Command> = expired
+        .into_iter()
+        .filter(|(key, cmd_pos)| {
+            index
+                .get(key)
+                .is_some_and(|entry| entry.value().load() == *cmd_pos)
+        })
+        .map(|(key, _)| Command::remove(key))
+        .collect();
+    if cmds.is_empty() {
+        return Ok(());
+    }
+    debug!("Removing {} expired keys", cmds.len());
+    writer.write(cmds)?;
+    // applies the removals, which nobody waits for
+    writer.apply_pending()
+}
@@ -0,0 +1,187 @@
+//! Hint files, `<gen>.hint`, listing where the values of a generation are.
+//!
+//! Compactions write one next to every generation they produce, so that
+//! `KvStore::open` builds the index from it instead of reading the whole log.
+//! A hint file starts with `HINT_MAGIC` and the format version, followed by a
+//! single record in the `log_format` framing holding every entry, so that one
+//! checksum covers the whole file.
+//!
+//! A hint is only trusted if the log it describes still has the length recorded
+//! in it. The log is replayed otherwise, as it is when the hint is missing or damaged.
+//!
+//! A hint also lists the removals, which compactions keep as long as older log
+//! files may hold the removed keys, and the generations compacted, which
+//! `KvStore::verify` reports as orphaned while their files are left.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::{log_format, CommandPos};
+use crate::{KvsError, Result};
+
+const HINT_MAGIC: [u8; 4] = *b"KVSH";
+const HINT_VERSION: u32 = 1;
+
+
+/// A command of the log file, without its value.
+pub(super) enum HintEntry {
+    Set { key: Vec<u8>, cmd_pos: CommandPos },
+    Remove { key: Vec<u8>, cmd_pos: CommandPos },
+}
+
+
+#[derive(Serialize, Deserialize)]
+struct Hint {
+    gen: u64,
+    // length of the log file when the hint was written
+    log_len: u64,
+    records: Vec<HintRecord>,
+    // generations read by the compaction that wrote the hint
+    compacted: Vec<u64>,
+}
+
+
+/// A `HintEntry` as stored, the generation being the hint's one.
+#[derive(Serialize, Deserialize)]
+enum HintRecord {
+    Set {
+        key: Vec<u8>,
+        pos: u64,
+        len: u64,
+        expires_at: Option<u64>,
+    },
+    Remove { key: Vec<u8>, pos: u64, len: u64 },
+}
+
+
+/// Writes the hint file of generation `gen`, whose log is `log_len` bytes long
+/// and holds the commands of `entries`, in the same order. The generation is
+/// written by the compaction of `compacted`.
+pub(super) fn write(
+    dir: &Path,
+    gen: u64,
+    log_len: u64,
+    entries: &[HintEntry],
+    compacted: &[u64],
+) -> Result<()> {
+    let records = entries
+        .iter()
+        .map(|entry| match entry {
+            HintEntry::Set { key, cmd_pos } => HintRecord::Set {
+                key: key.clone(),
+                pos: cmd_pos.pos,
+                len: cmd_pos.len,
+                expires_at: cmd_pos.expires_at,
+            },
+            HintEntry::Remove { key, cmd_pos } => HintRecord::Remove {
+                key: key.clone(),
+                pos: cmd_pos.pos,
+                len: cmd_pos.len,
+            },
+        })
+        .collect();
+    let hint = Hint {
+        gen,
+        log_len,
+        records,
+        compacted: compacted.to_vec(),
+    };
+    let mut writer = BufWriter::new(File::create(hint_path(dir, gen))?);
+    writer.write_all(&HINT_MAGIC)?;
+    writer.write_all(&HINT_VERSION.to_le_bytes())?;
+    log_format::write_record(&mut writer, &hint)?;
+    writer.flush()?;
+    writer.get_ref().sync_data()?;
+    Ok(())
+}
+
+
+/// Reads the hint file of generation `gen`, whose log is `log_len` bytes long.
+///
+/// Returns `None` if there is no usable hint, in which case the log must be replayed.
+pub(super) fn read(dir: &Path, gen: u64, log_len: u64) -> Option<Vec<HintEntry>> {
+    let entries = read_matching(dir, gen, log_len)?
+        .records
+        .into_iter()
+        .map(|record| match record {
+            HintRecord::Set {
+                key,
+                pos,
+                len,
+                expires_at,
+            } => {
+                let cmd_pos = CommandPos {
+                    expires_at,
+                    ..(gen, pos..pos + len).into()
+                };
+                HintEntry::Set { key, cmd_pos }
+            }
+            HintRecord::Remove { key, pos, len } => HintEntry::Remove {
+                key,
+                cmd_pos: (gen, pos..pos + len).into(),
+            },
+        })
+        .collect();
+    Some(entries)
+}
+
+
+/// Returns the generations compacted into generation `gen`, whose log is `log_len`
+/// bytes long, or `None` if it has no usable hint.
+pub(super) fn compacted(dir: &Path, gen: u64, log_len: u64) -> Option<Vec<u64>> {
+    read_matching(dir, gen, log_len).map(|hint| hint.compacted)
+}
+
+
+/// Removes the hint file of generation `gen`, if any.
+pub(super) fn remove(dir: &Path, gen: u64) -> io::Result<()> {
+    match fs::remove_file(hint_path(dir, gen)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+
+/// Returns `None` if there is no hint file, or if it cannot be used for the log.
+fn read_matching(dir: &Path, gen: u64, log_len: u64) -> Option<Hint> {
+    let hint = match read_hint(&hint_path(dir, gen)) {
+        Ok(hint) => hint?,
+        Err(e) => {
+            warn!("Ignoring the hint file of generation {}: {}", gen, e);
+            return None;
+        }
+    };
+    if hint.gen != gen || hint.log_len != log_len {
+        warn!("Ignoring the hint file of generation {}: it does not match the log", gen);
+        return None;
+    }
+    Some(hint)
+}
+
+
+/// Returns `None` if the file does not exist.
+fn read_hint(path: &Path) -> Result<Option<Hint>> {
+    let mut reader = match File::open(path) {
+        Ok(file) => BufReader::new(file),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut header = [0; 8];
+    reader.read_exact(&mut header)?;
+    if header[..4] != HINT_MAGIC || header[4..] != HINT_VERSION.to_le_bytes() {
+        return Err(KvsError::UnsupportedLogFormat("not a kvs hint file".to_owned()));
+    }
+    match log_format::read_record(&mut reader)? {
+        Some(hint) => Ok(Some(hint)),
+        None => Err(KvsError::Corruption("empty hint file".to_owned())),
+    }
+}
+
+
+pub(super) fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
@@ -0,0 +1,111 @@
+//! The log files of the first versions of the store: a stream of JSON commands
+//! with string keys and values, without a file header.
+//!
+//! `KvStore::open` rewrites them in the current format before loading them.
+
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::Deserializer;
+
+use super::{log_format, log_path, Command, CommandPos};
+use crate::{KvsError, Result};
+
+
+/// Command of the JSON log files.
+#[derive(Deserialize)]
+enum JsonCommand {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+
+impl JsonCommand {
+    fn into_command(self) -> Command {
+        match self {
+            JsonCommand::Set { key, value } => {
+                Command::set(key.into_bytes(), value.into_bytes(), None)
+            }
+            JsonCommand::Remove { key } => Command::remove(key.into_bytes()),
+        }
+    }
+}
+
+
+/// Returns true if the log file of `gen` in `dir` is a JSON log file.
+pub(super) fn is_json_log(dir: &Path, gen: u64) -> Result<bool> {
+    let mut first = [0; 1];
+    let read = File::open(log_path(dir, gen))?.read(&mut first)?;
+    Ok(read == 1 && first[0] == b'{')
+}
+
+
+/// Rewrites the JSON log file of `gen` in `dir` in the current format.
+///
+/// The new file is written aside and renamed over the old one, so a crash
+/// leaves either of them. A command torn by a crash at the end of the file is
+/// dropped, as `RecoveryMode::Truncate` would.
+pub(super) fn upgrade(dir: &Path, gen: u64) -> Result<()> {
+    let path = log_path(dir, gen);
+    let reader = BufReader::new(File::open(&path)?);
+    let tmp_path = dir.join(format!("{}.log.upgrade", gen));
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    log_format::write_header(&mut writer)?;
+
+    let mut count = 0;
+    for cmd in Deserializer::from_reader(reader).into_iter::<JsonCommand>() {
+        let cmd = match cmd {
+            Ok(cmd) => cmd.into_command(),
+            Err(e) if e.is_eof() => {
+                warn!("Dropped the torn command at the end of {}.log", gen);
+                break;
+            }
+            Err(e) if e.is_io() => return Err(KvsError::IoError),
+            Err(e) => {
+                return Err(KvsError::Corruption(format!(
+                    "undecodable JSON command in {}.log: {}",
+                    gen, e
+                )))
+            }
+        };
+        log_format::write_record(&mut writer, &cmd)?;
+        count += 1;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_data()?;
+    fs::rename(&tmp_path, &path)?;
+    // makes the rename durable
+    File::open(dir)?.sync_all()?;
+    info!("Upgraded the {} commands of {}.log to the current log format", count, gen);
+    Ok(())
+}
+
+
+/// Reads the JSON log file of `gen` in `dir` without upgrading it, calling `apply`
+/// with every command and its position in the file.
+///
+/// Returns the offset of the first command that cannot be read and why, if any.
+pub(super) fn replay(
+    dir: &Path,
+    gen: u64,
+    mut apply: impl FnMut(Command, CommandPos),
+) -> Result<Option<(u64, String)>> {
+    let reader = BufReader::new(File::open(log_path(dir, gen))?);
+    let mut stream = Deserializer::from_reader(reader).into_iter::<JsonCommand>();
+    let mut pos = 0;
+    loop {
+        match stream.next() {
+            Some(Ok(cmd)) => {
+                let end = stream.byte_offset() as u64;
+                apply(cmd.into_command(), (gen, pos..end).into());
+                pos = end;
+            }
+            Some(Err(e)) if e.is_io() => return Err(KvsError::IoError),
+            Some(Err(e)) => return Ok(Some((pos, e.to_string()))),
+            None => return Ok(None),
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! The lock file, `LOCK`, keeping two stores from writing the same directory.
+//!
+//! The lock is an advisory one, taken with `flock` on Unix and `LockFileEx` on
+//! Windows. The OS releases it when the process exits, so a crash never leaves
+//! the directory locked.
+//!
+//! Read-only stores take a shared lock instead, so that they can open the same
+//! directory together but not while a store writes it.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+use fs2::FileExt;
+
+use crate::{KvsError, Result};
+
+const LOCK_FILE: &str = "LOCK";
+
+
+/// Lock on a data directory, released when dropped.
+///
+/// An exclusive `DirLock` cannot be held on a directory along with any other
+/// `DirLock`, be it by another process or within the same one.
+#[derive(Debug)]
+pub struct DirLock {
+    // unlocked by closing it
+    _file: File,
+}
+
+
+impl DirLock {
+    /// Locks the directory at `dir`, which must exist.
+    ///
+    /// Fails with `KvsError::DirectoryInUse` if it is locked already.
+    pub fn acquire(dir: &Path) -> Result<DirLock> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(LOCK_FILE))?;
+        if file.try_lock_exclusive().is_err() {
+            return Err(KvsError::DirectoryInUse(dir.to_path_buf()));
+        }
+        Ok(DirLock { _file: file })
+    }
+
+    /// Takes a shared lock on the directory at `dir`, without writing to it.
+    ///
+    /// Fails with `KvsError::DirectoryInUse` if it is locked exclusively. Returns
+    /// `None` if no store ever locked the directory, since creating the lock file
+    /// would be a write.
+    pub fn acquire_shared(dir: &Path) -> Result<Option<DirLock>> {
+        let file = match File::open(dir.join(LOCK_FILE)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if file.try_lock_shared().is_err() {
+            return Err(KvsError::DirectoryInUse(dir.to_path_buf()));
+        }
+        Ok(Some(DirLock { _file: file }))
+    }
+}
@@ -0,0 +1,141 @@
+//! On-disk format of the `<gen>.log` files.
+//!
+//! Each file starts with a header made of `LOG_MAGIC` and the format version,
+//! followed by records. A record is the little-endian `u32` length of its payload,
+//! the CRC32 of the payload and the bincode-serialized payload itself.
+//!
+//! Version 2 added the expiry time to the set commands. Files of version 1 are
+//! still read, and rewritten in the current version by compactions. The JSON
+//! files of the versions before this format are converted on open, see `legacy`.
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{KvsError, Result};
+
+const LOG_MAGIC: [u8; 4] = *b"KVSL";
+/// Version of the files written.
+pub(super) const LOG_VERSION: u32 = 2;
+
+/// Length of the file header.
+pub(super) const HEADER_LEN: u64 = 8;
+/// Length of the length and checksum prefixing every record.
+const RECORD_HEADER_LEN: u64 = 8;
+
+
+/// Writes the file header. Returns the number of bytes written.
+pub(super) fn write_header<W: Write>(writer: &mut W) -> Result<u64> {
+    writer.write_all(&LOG_MAGIC)?;
+    writer.write_all(&LOG_VERSION.to_le_bytes())?;
+    Ok(HEADER_LEN)
+}
+
+
+/// Reads and checks the file header. Returns the format version of the file.
+///
+/// Returns `None` if the file is empty, which happens when a crash occurs right
+/// after the file is created.
+pub(super) fn read_header<R: Read>(reader: &mut R) -> Result<Option<u32>> {
+    let mut header = [0; HEADER_LEN as usize];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(None),
+        n if n < header.len() => return Err(corruption("torn file header")),
+        _ => {}
+    }
+    if header[0] == b'{' {
+        return Err(KvsError::UnsupportedLogFormat(
+            "JSON log file of an older version, open the store for writing to upgrade it"
+                .to_owned(),
+        ));
+    }
+    if header[..4] != LOG_MAGIC {
+        return Err(KvsError::UnsupportedLogFormat(
+            "not a kvs log file".to_owned(),
+        ));
+    }
+    let mut version = [0; 4];
+    version.copy_from_slice(&header[4..]);
+    let version = u32::from_le_bytes(version);
+    if version == 0 || version > LOG_VERSION {
+        return Err(KvsError::UnsupportedLogFormat(format!(
+            "unknown version {}",
+            version
+        )));
+    }
+    Ok(Some(version))
+}
+
+
+/// Serializes `value` as a record. Returns the number of bytes written.
+///
+/// Nothing is written if the payload is longer than `u32::MAX` bytes.
+pub(crate) fn write_record<W: Write, T: Serialize>(writer: &mut W, value: &T) -> Result<u64> {
+    let payload = bincode::serialize(value)?;
+    let len = u32::try_from(payload.len()).map_err(|_| {
+        KvsError::StringError(format!(
+            "A record of {} bytes is over the limit of {} bytes",
+            payload.len(),
+            u32::MAX
+        ))
+    })?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+    writer.write_all(&payload)?;
+    Ok(RECORD_HEADER_LEN + payload.len() as u64)
+}
+
+
+/// Reads the next record and checks its checksum.
+///
+/// Returns `None` at the end of the file, and `KvsError::Corruption` if the record
+/// is incomplete or damaged.
+pub(crate) fn read_record<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let mut header = [0; RECORD_HEADER_LEN as usize];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(None),
+        n if n < header.len() => return Err(corruption("torn record header")),
+        _ => {}
+    }
+    let mut len = [0; 4];
+    len.copy_from_slice(&header[..4]);
+    let len = u32::from_le_bytes(len) as u64;
+    let mut crc = [0; 4];
+    crc.copy_from_slice(&header[4..]);
+    let crc = u32::from_le_bytes(crc);
+
+    // `take` keeps a damaged length from allocating a huge buffer
+    let mut payload = Vec::new();
+    reader.take(len).read_to_end(&mut payload)?;
+    if (payload.len() as u64) < len {
+        return Err(corruption("torn record"));
+    }
+    if crc32fast::hash(&payload) != crc {
+        return Err(corruption("checksum mismatch"));
+    }
+    bincode::deserialize(&payload)
+        .map(Some)
+        .map_err(|e| corruption(&format!("undecodable record: {}", e)))
+}
+
+
+/// Reads until `buf` is full or the end of file is reached. Returns the number of bytes read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(read)
+}
+
+
+fn corruption(reason: &str) -> KvsError {
+    KvsError::Corruption(reason.to_owned())
+}
@@ -0,0 +1,1268 @@
+use std::cell::{Cell, RefCell};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::{Bound, Range, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use self::compaction::{Compactor, LogStats};
+use self::expiry::Sweeper;
+use self::hint::HintEntry;
+use self::snapshot::Snapshots;
+use self::sync::Syncer;
+
+use crossbeam::atomic::AtomicCell;
+use crossbeam_skiplist::SkipMap;
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use super::ttl::{expiry_time, now_millis};
+use super::{
+    BatchOp, KvsEngine, KvsSnapshot, ScanIter, ScanOptions, Transaction, WriteBatch, MAX_TRANSACTION_ATTEMPTS,
+};
+use crate::{KvsError, Result};
+
+mod checkpoint;
+mod compaction;
+mod expiry;
+mod hint;
+mod legacy;
+mod lock;
+// also frames the records of `dump`
+pub(super) mod log_format;
+mod options;
+mod recovery;
+mod snapshot;
+mod sync;
+mod verify;
+
+pub use self::lock::DirLock;
+pub use self::options::{KvStoreOptions, SyncPolicy};
+pub use self::recovery::{RecoveryMode, Repair, RepairReport};
+pub use self::snapshot::KvStoreSnapshot;
+pub use self::verify::{GenerationReport, VerifyReport};
+
+
+/// Maps each key to the position of its latest value.
+type Index = SkipMap<Vec<u8>, AtomicCell<CommandPos>>;
+
+
+// KvsEngine behaves like a handle to another object, and because that object is shared between threads, it probably needs to live on the heap, 
+// and because that shared state can't be mutable it needs to be protected by some synchronization primitive
+// move the data inside your implementation of KvsEngine, 
+// KvStore onto the heap using a thread-safe shared pointer type and protect it behind a lock of your choosing
+#[derive(Clone)]
+pub struct KvStore {
+    // map generation number to the file reader
+    index: Arc<Index>,
+    reader: KvStoreReader,
+    // `None` when the store is opened read-only
+    writer: Option<Arc<Mutex<KvStoreWriter>>>,
+    syncer: Option<Arc<Syncer>>,
+    snapshots: Arc<Snapshots>,
+    // declared last so that it is released once the writer is dropped
+    _lock: Option<Arc<DirLock>>,
+}
+
+
+impl KvStore {
+    /// Opens a `KvStore` at the given path, truncating damaged log tails.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Ok(KvStore::open_with_recovery(path, RecoveryMode::default())?.0)
+    }
+
+    /// Opens a `KvStore` at the given path, dealing with damaged log records
+    /// according to `mode`.
+    ///
+    /// Returns the store along with a report of the bytes dropped from each generation.
+    pub fn open_with_recovery(
+        path: impl Into<PathBuf>,
+        mode: RecoveryMode,
+    ) -> Result<(KvStore, RepairReport)> {
+        KvStore::open_inner(path.into(), &KvStoreOptions::new().recovery_mode(mode))
+    }
+
+    /// Opens the `KvStore` at the given path read-only, see `KvStoreOptions::read_only`.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, &KvStoreOptions::new().read_only(true))
+    }
+
+    /// Opens a `KvStore` at the given path with the given options.
+    ///
+    /// Repairs made to damaged log files are logged.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: &KvStoreOptions) -> Result<KvStore> {
+        Ok(KvStore::open_inner(path.into(), options)?.0)
+    }
+
+    fn open_inner(path: PathBuf, options: &KvStoreOptions) -> Result<(KvStore, RepairReport)> {
+        options.validate()?;
+        let path = Arc::new(path);
+        // loading may repair the log files, so the lock is taken first
+        let lock = if options.read_only {
+            if !path.is_dir() {
+                return Err(KvsError::KvPathNotFoundError);
+            }
+            DirLock::acquire_shared(&path)?.map(Arc::new)
+        } else {
+            fs::create_dir_all(&*path)?;
+            Some(Arc::new(DirLock::acquire(&path)?))
+        };
+
+        let mut readers = BTreeMap::new();
+        let index = Arc::new(SkipMap::new());
+
+        let gen_list = sorted_gen_list(&path)?;
+        if !options.read_only {
+            for &gen in &gen_list {
+                if legacy::is_json_log(&path, gen)? {
+                    legacy::upgrade(&path, gen)?;
+                }
+            }
+        }
+        let mut stats = LogStats::default();
+        let mut report = RepairReport::default();
+
+        for &gen in &gen_list {
+            let mut reader = LogReader::new(File::open(log_path(&path, gen))?)?;
+            let repair = if load_hint(&path, gen, &mut reader, &index, &mut stats)? {
+                None
+            } else {
+                let newest = gen_list.last() == Some(&gen);
+                load(&path, gen, newest, &mut reader, &index, &mut stats, options)?
+            };
+            stats.add_file(gen, reader.reader.pos, reader.version);
+            report.repairs.extend(repair);
+            readers.insert(gen, reader);
+        }
+
+        let snapshots = Arc::new(Snapshots::default());
+        let reader = KvStoreReader {
+            path: Arc::clone(&path),
+            epoch: Arc::new(AtomicU64::new(0)),
+            seen_epoch: Cell::new(0),
+            readers: RefCell::new(readers),
+        };
+        if options.read_only {
+            let store = KvStore {
+                reader,
+                index,
+                writer: None,
+                syncer: None,
+                snapshots,
+                _lock: lock,
+            };
+            return Ok((store, report));
+        }
+
+        let current_gen = gen_list.last().unwrap_or(&0) + 1;
+        let writer = new_log_file(&path, current_gen)?;
+        stats.add_file(current_gen, writer.pos, log_format::LOG_VERSION);
+        let syncer = Syncer::new(options.sync_policy, writer.get_ref().try_clone()?)?;
+
+        let writer = Arc::new(Mutex::new(KvStoreWriter {
+            writer,
+            current_gen,
+            stats,
+            options: options.clone(),
+            path: Arc::clone(&path),
+            index: Arc::clone(&index),
+            syncer: Arc::clone(&syncer),
+            snapshots: Arc::clone(&snapshots),
+            compactor: None,
+            sweeper: None,
+            poisoned: false,
+            pending: VecDeque::new(),
+            last_applied: 0,
+            failed: BTreeSet::new(),
+        }));
+        let compactor = Compactor::spawn(
+            Arc::downgrade(&writer),
+            Arc::clone(&reader.epoch),
+            Arc::clone(&index),
+            Arc::clone(&snapshots),
+            Arc::clone(&path),
+        )?;
+        let sweeper = Sweeper::spawn(
+            Arc::downgrade(&writer),
+            Arc::clone(&index),
+            options.sweep_interval,
+        )?;
+        {
+            let mut writer = writer.lock().unwrap();
+            writer.compactor = Some(compactor);
+            writer.sweeper = Some(sweeper);
+        }
+
+        let store = KvStore {
+            reader,
+            index,
+            writer: Some(writer),
+            syncer: Some(syncer),
+            snapshots,
+            _lock: lock,
+        };
+        Ok((store, report))
+    }
+
+    /// Checks the store at the given path without opening it for writing.
+    ///
+    /// Every log file is read the way `open` loads it, ignoring hint files, and
+    /// the damage found is reported instead of being repaired. Fails with
+    /// `KvsError::DirectoryInUse` if a store is open for writing on the directory.
+    pub fn verify(path: impl AsRef<Path>) -> Result<VerifyReport> {
+        verify::verify(path.as_ref())
+    }
+
+    fn writer(&self) -> Result<&Mutex<KvStoreWriter>> {
+        self.writer.as_deref().ok_or(KvsError::ReadOnly)
+    }
+
+    /// Reads the value of `key` from its index entry `cmd_pos`.
+    ///
+    /// Returns `None` if the value has expired.
+    fn read_value(&self, key: &[u8], cmd_pos: CommandPos) -> Result<Option<Vec<u8>>> {
+        read_value(&self.reader, cmd_pos, || {
+            self.index.get(key).map(|entry| entry.value().load())
+        })
+    }
+
+    /// Waits for the write with sequence number `seq` to be synced as the policy
+    /// requires. With `SyncPolicy::Always`, the write is applied afterwards.
+    fn commit(&self, seq: u64) -> Result<()> {
+        let syncer = match &self.syncer {
+            Some(syncer) => syncer,
+            None => return Ok(()),
+        };
+        let synced = syncer.commit(seq);
+        if syncer.policy() == SyncPolicy::Always {
+            return self.writer()?.lock().unwrap().settle(seq, synced);
+        }
+        synced
+    }
+}
+
+
+impl KvsEngine for KvStore {
+    type Snapshot = KvStoreSnapshot;
+
+    fn set<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(&self, key: K, value: V) -> Result<()> {
+        // is unlocked when data goes out of scope
+        let seq = self.writer()?.lock().unwrap().set(key.into(), value.into())?;
+        self.commit(seq)
+    }
+
+    fn set_with_ttl<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(
+        &self,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> Result<()> {
+        let seq = self.writer()?.lock().unwrap().set_with_expiry(
+            key.into(),
+            value.into(),
+            Some(expiry_time(ttl)),
+        )?;
+        self.commit(seq)
+    }
+
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        match self.index.get(key) {
+            Some(entry) => self.read_value(key, entry.value().load()),
+            None => Ok(None),
+        }
+    }
+
+    fn remove<K: AsRef<[u8]>>(&self, key: K) -> Result<()> {
+        let seq = self.writer()?.lock().unwrap().remove(key.as_ref())?;
+        self.commit(seq)
+    }
+
+    fn compare_and_swap<K: Into<Vec<u8>>>(
+        &self,
+        key: K,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let key = key.into();
+        let seq = {
+            let mut writer = self.writer()?.lock().unwrap();
+            // no write can happen while the lock is held
+            writer.apply_pending()?;
+            let current = match self.index.get(&key) {
+                Some(entry) => self.read_value(&key, entry.value().load())?,
+                None => None,
+            };
+            if current != expected {
+                return Ok(false);
+            }
+            match new {
+                Some(value) => writer.set(key, value)?,
+                None if current.is_some() => writer.remove(&key)?,
+                // the key is missing already
+                None => return Ok(true),
+            }
+        };
+        self.commit(seq)?;
+        Ok(true)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let seq = self.writer()?.lock().unwrap().write_batch(batch)?;
+        self.commit(seq)
+    }
+
+    /// The index is read as the iterator advances, so keys written in the
+    /// meantime may be returned or not, and keys removed are skipped. Scan a
+    /// snapshot for a stable view.
+    fn scan<K, R>(&self, range: R, options: ScanOptions) -> Result<ScanIter>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let entries = IndexRange {
+            index: Arc::clone(&self.index),
+            start: range.start_bound().map(|key| key.as_ref().to_vec()),
+            end: range.end_bound().map(|key| key.as_ref().to_vec()),
+            reverse: options.reverse,
+        };
+        let store = self.clone();
+        let pairs = entries.filter_map(move |(key, cmd_pos)| {
+            store
+                .read_value(&key, cmd_pos)
+                .transpose()
+                .map(|value| value.map(|value| (key, value)))
+        });
+        Ok(Box::new(options.limited(pairs)))
+    }
+
+    /// The transaction reads from a snapshot. Its writes are applied as a batch,
+    /// unless one of the keys it read or wrote was written since the snapshot.
+    fn transaction<F, T>(&self, f: F) -> Result<T>
+    where
+        F: Fn(&mut Transaction<'_>) -> Result<T>,
+    {
+        for _ in 0..MAX_TRANSACTION_ATTEMPTS {
+            let snapshot = self.snapshot()?;
+            let mut txn = Transaction::new(Box::new(|key| snapshot.get(key)));
+            let res = f(&mut txn)?;
+            let (keys, batch) = txn.into_parts();
+            if batch.is_empty() {
+                // the snapshot made the reads consistent already
+                return Ok(res);
+            }
+            let seq = {
+                let mut writer = self.writer()?.lock().unwrap();
+                writer.apply_pending()?;
+                let snapshots = snapshot.snapshots();
+                if keys.iter().any(|key| snapshots.written_since(key, snapshot.seq())) {
+                    continue;
+                }
+                writer.write_batch(batch)?
+            };
+            self.commit(seq)?;
+            return Ok(res);
+        }
+        Err(KvsError::Conflict)
+    }
+
+    /// Sealed log files are hard-linked into `dest`, the active one copied up to
+    /// the last write. A snapshot is held meanwhile, so that compactions do not
+    /// remove the files before they are linked.
+    ///
+    /// The files of a read-only store are copied, since they may hold damaged
+    /// records which opening the checkpoint would repair.
+    fn checkpoint<P: AsRef<Path>>(&self, dest: P) -> Result<()> {
+        let path = &self.reader.path;
+        let (sealed, active, _snapshot) = match &self.writer {
+            Some(writer) => {
+                let mut writer = writer.lock().unwrap();
+                // the active file is copied up to the writes applied
+                writer.apply_pending()?;
+                let snapshot = KvStoreSnapshot::new(writer.last_applied, self);
+                let sealed: Vec<u64> = writer
+                    .stats
+                    .gens()
+                    .filter(|&gen| gen != writer.current_gen)
+                    .collect();
+                (sealed, Some((writer.current_gen, writer.writer.pos)), Some(snapshot))
+            }
+            // nothing is ever written or compacted
+            None => (sorted_gen_list(path)?, None, None),
+        };
+        checkpoint::write(path, dest.as_ref(), &sealed, active, self.writer.is_some())
+    }
+
+    fn snapshot(&self) -> Result<KvStoreSnapshot> {
+        let snapshot = match &self.writer {
+            Some(writer) => {
+                let writer = writer.lock().unwrap();
+                // the pending writes are recorded as they are applied
+                KvStoreSnapshot::new(writer.last_applied, self)
+            }
+            // nothing is ever written
+            None => KvStoreSnapshot::new(0, self),
+        };
+        Ok(snapshot)
+    }
+
+    fn flush(&self) -> Result<()> {
+        if let Some(writer) = &self.writer {
+            let mut writer = writer.lock().unwrap();
+            writer.writer.flush()?;
+            writer.apply_pending()?;
+        }
+        if let Some(syncer) = &self.syncer {
+            syncer.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Reads the value at `cmd_pos`, `locate` giving its current position.
+///
+/// Returns `None` if the value has expired.
+fn read_value<F>(reader: &KvStoreReader, mut cmd_pos: CommandPos, locate: F) -> Result<Option<Vec<u8>>>
+where
+    F: Fn() -> Option<CommandPos>,
+{
+    let now = now_millis();
+    loop {
+        if cmd_pos.is_expired(now) {
+            return Ok(None);
+        }
+        match reader.read_command(cmd_pos) {
+            Ok(Command::Set { value, .. }) => return Ok(Some(value)),
+            Ok(_) => return Err(KvsError::UnexpectedCommandType),
+            // a compaction may have moved the value in the meantime and removed
+            // its file, `locate` gives the new location then
+            Err(e) => {
+                let new_pos = match locate() {
+                    Some(new_pos) => new_pos,
+                    None => return Ok(None),
+                };
+                if new_pos == cmd_pos {
+                    return Err(e);
+                }
+                cmd_pos = new_pos;
+            }
+        }
+    }
+}
+
+
+/// Iterates over the index entries in a range, looking each one up from the
+/// previous key, so that it holds no borrow of the index.
+struct IndexRange {
+    index: Arc<Index>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    reverse: bool,
+}
+
+
+impl Iterator for IndexRange {
+    type Item = (Vec<u8>, CommandPos);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, cmd_pos) = {
+            let range = (
+                self.start.as_ref().map(Vec::as_slice),
+                self.end.as_ref().map(Vec::as_slice),
+            );
+            let mut entries = self.index.range::<[u8], _>(range);
+            let entry = if self.reverse {
+                entries.next_back()?
+            } else {
+                entries.next()?
+            };
+            (entry.key().clone(), entry.value().load())
+        };
+        if self.reverse {
+            self.end = Bound::Excluded(key.clone());
+        } else {
+            self.start = Bound::Excluded(key.clone());
+        }
+        Some((key, cmd_pos))
+    }
+}
+
+
+/// A single thread reader.
+///
+/// Each `KvStore` instance has its own `KvStoreReader` and
+/// `KvStoreReader`s open the same files separately. So the user
+/// can read concurrently through multiple `KvStore`s in different
+/// threads.
+struct KvStoreReader {
+    path: Arc<PathBuf>,
+    // number of compactions finished, each of them removes log files
+    epoch: Arc<AtomicU64>,
+    // the epoch when the stale handles were last closed
+    seen_epoch: Cell<u64>,
+    // for thread-safe mutably borrow? will throw an error if borrow an already
+    // borrowed value
+    readers: RefCell<BTreeMap<u64, LogReader>>,
+}
+
+
+impl KvStoreReader {
+    /// Closes every file handle once a compaction has finished since the last call.
+    ///
+    /// `epoch` is increased after a compaction removes the log files it compacted,
+    /// whose handles would otherwise keep them on disk. The handles still in use
+    /// are opened again on demand.
+    fn close_stale_handles(&self) {
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        if self.seen_epoch.get() != epoch {
+            self.readers.borrow_mut().clear();
+            self.seen_epoch.set(epoch);
+        }
+    }
+
+    // Read the log file at the given `CommandPos` and deserialize it to `Command`.
+    fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
+        self.close_stale_handles();
+        let mut readers = self.readers.borrow_mut();
+        // Open the file if we haven't opened it in this `KvStoreReader`.
+        let reader = match readers.entry(cmd_pos.gen) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(LogReader::open(File::open(log_path(&self.path, cmd_pos.gen))?)?)
+            }
+        };
+        reader.reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        let version = reader.version;
+        decode_command(&mut (&mut reader.reader).take(cmd_pos.len), version)?
+            .ok_or_else(|| KvsError::Corruption("missing record".to_owned()))
+    }
+}
+
+
+impl Clone for KvStoreReader {
+    fn clone(&self) -> KvStoreReader {
+        KvStoreReader {
+            path: Arc::clone(&self.path),
+            epoch: Arc::clone(&self.epoch),
+            seen_epoch: Cell::new(self.epoch.load(Ordering::SeqCst)),
+            // don't use other KvStoreReader's readers
+            readers: RefCell::new(BTreeMap::new()),
+        }
+    }
+}
+
+
+struct KvStoreWriter {
+    writer: BufWriterWithPos<File>,
+    current_gen: u64,
+    // size and garbage of the log files
+    stats: LogStats,
+    options: KvStoreOptions,
+    path: Arc<PathBuf>,
+    index: Arc<Index>,
+    syncer: Arc<Syncer>,
+    snapshots: Arc<Snapshots>,
+    // set right after the writer is shared, see `KvStore::open_inner`
+    compactor: Option<Compactor>,
+    sweeper: Option<Sweeper>,
+    // set when a failed write could not be cut off the log
+    poisoned: bool,
+    // writes waiting for their sync before being applied, see `settle`
+    pending: VecDeque<PendingWrite>,
+    // sequence number of the last write applied to the index
+    last_applied: u64,
+    // sequence numbers of the pending writes dropped after a failed sync
+    failed: BTreeSet<u64>,
+}
+
+
+/// A write appended to the active log file, not yet applied to the index.
+struct PendingWrite {
+    seq: u64,
+    // position of the write in the active log file, and its length
+    start: u64,
+    len: u64,
+    written: Vec<(Command, CommandPos)>,
+}
+
+impl KvStoreWriter {
+    /// Returns the sequence number of the write, see `Syncer`.
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<u64> {
+        self.set_with_expiry(key, value, None)
+    }
+
+    /// Sets a value expiring at the given time, in milliseconds since the Unix epoch.
+    ///
+    /// Returns the sequence number of the write, see `Syncer`.
+    fn set_with_expiry(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<u64> {
+        self.write(vec![Command::set(key, value, expires_at)])
+    }
+
+    /// Returns the sequence number of the write, see `Syncer`.
+    fn remove(&mut self, key: &[u8]) -> Result<u64> {
+        self.apply_pending()?;
+        let now = now_millis();
+        let exists = self
+            .index
+            .get(key)
+            .is_some_and(|entry| !entry.value().load().is_expired(now));
+        if exists {
+            self.write(vec![Command::remove(key.to_vec())])
+        } else {
+            Err(KvsError::KeyNotFound)
+        }
+    }
+
+    /// Writes the batch behind a `Command::Batch` header, so that loading the log
+    /// drops it unless every command made it to disk.
+    ///
+    /// Returns the sequence number of the write, see `Syncer`.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<u64> {
+        let mut cmds = Vec::with_capacity(batch.len() + 1);
+        cmds.push(Command::Batch {
+            len: batch.len() as u64,
+        });
+        cmds.extend(batch.ops.into_iter().map(|op| match op {
+            BatchOp::Set { key, value } => Command::set(key, value, None),
+            BatchOp::Remove { key } => Command::remove(key),
+        }));
+        self.write(cmds)
+    }
+
+    /// Writes the commands to the active log file, flushes them to the OS and
+    /// applies them to the index. With `SyncPolicy::Always`, they are only applied
+    /// once synced, see `settle`, so that readers never see a write that failed.
+    ///
+    /// If writing fails, the commands already written are cut off the log, so
+    /// that a part of a batch is not loaded with the writes following it. If that
+    /// fails too, the writer refuses every later write.
+    ///
+    /// Returns the sequence number of the write.
+    fn write(&mut self, cmds: Vec<Command>) -> Result<u64> {
+        if self.poisoned {
+            return Err(KvsError::WriterPoisoned);
+        }
+        let start = self.writer.pos;
+        let written = match self.append(cmds) {
+            Ok(written) => written,
+            Err(e) => {
+                self.cut_off(start);
+                return Err(e);
+            }
+        };
+        let write = PendingWrite {
+            seq: self.syncer.appended(),
+            start,
+            len: self.writer.pos - start,
+            written,
+        };
+        let seq = write.seq;
+        if self.options.sync_policy == SyncPolicy::Always {
+            self.pending.push_back(write);
+        } else {
+            self.apply(write);
+        }
+        self.maintain()?;
+        Ok(seq)
+    }
+
+    /// Applies the write `seq` once its sync, made without the writer lock held,
+    /// is over, along with every other write synced meanwhile.
+    ///
+    /// If the sync failed, the writes still waiting for one are cut off the log
+    /// and fail, unless a later sync covered the write `seq` already.
+    fn settle(&mut self, seq: u64, synced: Result<()>) -> Result<()> {
+        match synced {
+            Ok(()) => self.apply_synced(),
+            Err(e) if seq > self.last_applied => {
+                self.drop_pending();
+                self.failed.remove(&seq);
+                return Err(e);
+            }
+            Err(_) => {}
+        }
+        // dropped after the failed sync of another write
+        if self.failed.remove(&seq) {
+            return Err(KvsError::IoError);
+        }
+        Ok(())
+    }
+
+    /// Syncs and applies the writes waiting for a sync, for the writes that read
+    /// the index first.
+    fn apply_pending(&mut self) -> Result<()> {
+        let seq = match self.pending.back() {
+            Some(write) => write.seq,
+            None => return Ok(()),
+        };
+        if let Err(e) = self.syncer.sync_upto(seq) {
+            self.drop_pending();
+            return Err(e);
+        }
+        self.apply_synced();
+        Ok(())
+    }
+
+    /// Applies the pending writes that are synced, in order.
+    fn apply_synced(&mut self) {
+        let synced = self.syncer.synced();
+        while self.pending.front().is_some_and(|write| write.seq <= synced) {
+            let write = self.pending.pop_front().unwrap();
+            self.apply(write);
+        }
+    }
+
+    /// Cuts the pending writes off the log, after a failed sync.
+    fn drop_pending(&mut self) {
+        if let Some(write) = self.pending.front() {
+            self.cut_off(write.start);
+        }
+        self.failed.extend(self.pending.drain(..).map(|write| write.seq));
+    }
+
+    /// Applies a write of the active log file to the index.
+    fn apply(&mut self, write: PendingWrite) {
+        let seq = write.seq;
+        self.stats.grow(self.current_gen, write.len);
+        for (cmd, cmd_pos) in write.written {
+            self.snapshots.record(&self.index, &cmd, seq);
+            let cmd_pos = CommandPos { seq, ..cmd_pos };
+            apply_command(&self.index, cmd, cmd_pos, &mut self.stats);
+        }
+        self.last_applied = seq;
+    }
+
+    /// Drops a failed write, which starts at `start`, from the active log file.
+    fn cut_off(&mut self, start: u64) {
+        if let Err(e) = self.writer.truncate(start) {
+            error!("Failed to cut a failed write off the log: {}", e);
+            self.poisoned = true;
+        }
+    }
+
+    /// Writes the commands to the active log file and flushes them to the OS.
+    ///
+    /// Returns the commands along with their positions.
+    fn append(&mut self, cmds: Vec<Command>) -> Result<Vec<(Command, CommandPos)>> {
+        let mut written = Vec::with_capacity(cmds.len());
+        for cmd in cmds {
+            let pos = self.writer.pos;
+            log_format::write_record(&mut self.writer, &cmd)?;
+            written.push((cmd, (self.current_gen, pos..self.writer.pos).into()));
+        }
+        self.writer.flush()?;
+        Ok(written)
+    }
+
+    /// Makes `gen` the active log file.
+    fn switch_to(&mut self, gen: u64) -> Result<()> {
+        let writer = new_log_file(&self.path, gen)?;
+        self.syncer.switch_file(writer.get_ref().try_clone()?)?;
+        // the previous file is synced, and the pending writes must be applied
+        // before the generation they were written to is left
+        self.apply_synced();
+        self.current_gen = gen;
+        self.writer = writer;
+        self.stats.add_file(gen, self.writer.pos, log_format::LOG_VERSION);
+        Ok(())
+    }
+
+    /// Rotates the active log file and starts a compaction when they are due.
+    fn maintain(&mut self) -> Result<()> {
+        if let Some(max_file_size) = self.options.max_file_size {
+            if self.writer.pos >= max_file_size {
+                self.switch_to(self.current_gen + 1)?;
+            }
+        }
+        let ratio = self.stats.garbage() as f64 / self.stats.total_size().max(1) as f64;
+        if self.stats.garbage() > self.options.compaction_threshold
+            && ratio >= self.options.compaction_ratio
+        {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Lets the compaction thread clear stale entries in the log files whose share
+    /// of garbage exceeds `file_dead_ratio`, the active one included, and switches
+    /// writes to a new generation, leaving a gap of generations for the compaction output.
+    ///
+    /// Does nothing if a compaction is still in progress or no file is worth compacting.
+    fn compact(&mut self) -> Result<()> {
+        match &self.compactor {
+            Some(compactor) if !compactor.is_running() => {}
+            _ => return Ok(()),
+        }
+        // nothing was worth compacting last time, and nothing changed since
+        let now = now_millis();
+        if !self.stats.changed(now) {
+            return Ok(());
+        }
+        let gens = self.stats.select(self.options.file_dead_ratio, now);
+        if gens.is_empty() {
+            return Ok(());
+        }
+        // the generations right after the current one are for the compaction files,
+        // enough of them to hold the live entries without exceeding the max file size
+        let reserved = match self.options.max_file_size {
+            // one more for the rounding, and one more in case the live entries grow
+            // when rewritten in the current format
+            Some(max_file_size) => self.stats.live_size(&gens, now) / max_file_size + 2,
+            None => 1,
+        };
+        let compaction_gens = self.current_gen + 1..self.current_gen + 1 + reserved;
+        self.switch_to(compaction_gens.end)?;
+
+        if let Some(compactor) = &self.compactor {
+            compactor.start(gens, compaction_gens, self.options.max_file_size);
+        }
+        Ok(())
+    }
+}
+
+
+/// Points `key` to `cmd_pos` in the index and returns its previous position.
+///
+/// Existing entries are updated in place, since replacing them would make the
+/// key briefly missing for concurrent readers. Must be called with the writer
+/// lock held.
+fn update_index(index: &Index, key: Vec<u8>, cmd_pos: CommandPos) -> Option<CommandPos> {
+    match index.get(&key) {
+        Some(entry) => Some(entry.value().swap(cmd_pos)),
+        None => {
+            index.insert(key, AtomicCell::new(cmd_pos));
+            None
+        }
+    }
+}
+
+
+/// Applies a command written at `cmd_pos` to the index, and counts the commands
+/// it makes stale as garbage.
+fn apply_command(index: &Index, cmd: Command, cmd_pos: CommandPos, stats: &mut LogStats) {
+    match cmd {
+        Command::Set {
+            key, expires_at, ..
+        } => {
+            let cmd_pos = CommandPos {
+                expires_at,
+                ..cmd_pos
+            };
+            apply_set(index, key, cmd_pos, stats);
+        }
+        Command::Remove { key } => {
+            apply_remove(index, &key, stats);
+            // the "remove" command itself is garbage too, although compactions keep
+            // it as long as an older log file may hold the key
+            stats.add_garbage(&cmd_pos);
+        }
+        // the header is useless once the batch is read
+        Command::Batch { .. } => stats.add_garbage(&cmd_pos),
+    }
+}
+
+
+fn apply_set(index: &Index, key: Vec<u8>, cmd_pos: CommandPos, stats: &mut LogStats) {
+    stats.add_live(&cmd_pos);
+    if let Some(old_cmd) = update_index(index, key, cmd_pos) {
+        stats.add_garbage(&old_cmd);
+    }
+}
+
+
+fn apply_remove(index: &Index, key: &[u8], stats: &mut LogStats) {
+    if let Some(old_cmd) = index.remove(key) {
+        stats.add_garbage(&old_cmd.value().load());
+    }
+}
+
+
+/// Create a new log file with given generation number and write its header.
+///
+/// Returns the writer to the log.
+fn new_log_file(dir: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
+    let path = log_path(dir, gen);
+    let mut writer = BufWriterWithPos::new(
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?,
+    )?;
+    if writer.pos == 0 {
+        log_format::write_header(&mut writer)?;
+        writer.flush()?;
+        // makes the new directory entry durable, or the whole file could be
+        // lost along with the writes synced to it
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(writer)
+}
+
+
+/// Returns sorted generation numbers in the given directory
+fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = fs::read_dir(path)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .map(|s| s.trim_end_matches(".log"))
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    gen_list.sort_unstable();
+    Ok(gen_list)
+}
+
+
+/// Load the whole log file and store value locations in the index map.
+///
+/// Loading stops at the first damaged record, typically left by a torn write
+/// in the `newest` generation, which is then repaired according to the recovery mode.
+///
+/// Returns the repair made, if any.
+fn load(
+    path: &Path,
+    gen: u64,
+    newest: bool,
+    log_reader: &mut LogReader,
+    index: &Index,
+    stats: &mut LogStats,
+    options: &KvStoreOptions,
+) -> Result<Option<Repair>> {
+    let damage = replay(gen, log_reader, |cmd, cmd_pos| {
+        apply_command(index, cmd, cmd_pos, stats)
+    })?;
+    let repair = match damage {
+        Some((offset, reason)) => Some(recovery::repair(path, gen, offset, reason, newest, options)?),
+        None => None,
+    };
+    Ok(repair)
+}
+
+
+/// Reads the whole log file, passing its commands to `apply` in order. The
+/// commands of a batch are only passed once the whole batch is read.
+///
+/// Reading stops at the first damaged record. Returns its offset and what is
+/// wrong with it, if any. An incomplete batch is dropped altogether, so the
+/// offset is then the one of the batch.
+fn replay(
+    gen: u64,
+    log_reader: &mut LogReader,
+    mut apply: impl FnMut(Command, CommandPos),
+) -> Result<Option<(u64, String)>> {
+    let reader = &mut log_reader.reader;
+    // To make sure we read from the beginning of the file
+    reader.seek(SeekFrom::Start(0))?;
+    let version = match log_format::read_header(reader) {
+        Ok(Some(version)) => version,
+        Ok(None) => return Ok(None),
+        Err(KvsError::Corruption(reason)) => return Ok(Some((0, reason))),
+        Err(e) => return Err(e),
+    };
+    log_reader.version = version;
+    let mut pos = reader.pos;
+    // the batch being read, applied once all its commands are read
+    let mut batch: Option<PendingBatch> = None;
+    let damage = loop {
+        let cmd = match decode_command(reader, version) {
+            Ok(Some(cmd)) => cmd,
+            Ok(None) => match &batch {
+                Some(_) => break Some("incomplete batch".to_owned()),
+                None => break None,
+            },
+            Err(KvsError::Corruption(reason)) => break Some(reason),
+            Err(e) => return Err(e),
+        };
+        let cmd_pos: CommandPos = (gen, pos..reader.pos).into();
+        pos = reader.pos;
+        match (&mut batch, cmd) {
+            (Some(_), Command::Batch { .. }) => break Some("batch inside a batch".to_owned()),
+            (None, Command::Batch { len }) => {
+                batch = Some(PendingBatch {
+                    start: cmd_pos.pos,
+                    remaining: len,
+                    cmds: vec![(Command::Batch { len }, cmd_pos)],
+                });
+            }
+            (Some(pending), cmd) => {
+                pending.remaining -= 1;
+                pending.cmds.push((cmd, cmd_pos));
+            }
+            (None, cmd) => apply(cmd, cmd_pos),
+        }
+        if batch.as_ref().is_some_and(|pending| pending.remaining == 0) {
+            for (cmd, cmd_pos) in batch.take().unwrap().cmds {
+                apply(cmd, cmd_pos);
+            }
+        }
+    };
+    Ok(damage.map(|reason| (batch.map_or(pos, |pending| pending.start), reason)))
+}
+
+
+/// Applies the commands listed in the hint file of the generation to the index,
+/// without reading the log file past its header.
+///
+/// Returns false if there is no usable hint and the log file must be loaded instead.
+fn load_hint(
+    path: &Path,
+    gen: u64,
+    log_reader: &mut LogReader,
+    index: &Index,
+    stats: &mut LogStats,
+) -> Result<bool> {
+    let reader = &mut log_reader.reader;
+    let log_len = reader.seek(SeekFrom::End(0))?;
+    let entries = match hint::read(path, gen, log_len) {
+        Some(entries) => entries,
+        None => return Ok(false),
+    };
+    reader.seek(SeekFrom::Start(0))?;
+    log_reader.version = match log_format::read_header(reader) {
+        Ok(Some(version)) => version,
+        _ => return Ok(false),
+    };
+    reader.seek(SeekFrom::End(0))?;
+    for entry in entries {
+        match entry {
+            HintEntry::Set { key, cmd_pos } => apply_set(index, key, cmd_pos, stats),
+            // only a compaction writes hints, and it keeps the removal without
+            // counting it as garbage
+            HintEntry::Remove { key, .. } => apply_remove(index, &key, stats),
+        }
+    }
+    Ok(true)
+}
+
+
+/// A batch read from the log but not applied to the index yet.
+struct PendingBatch {
+    // position of the batch header
+    start: u64,
+    // number of commands of the batch still to read
+    remaining: u64,
+    cmds: Vec<(Command, CommandPos)>,
+}
+
+
+
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
+
+/// Struct representing a command
+#[derive(Serialize, Deserialize, Debug)]
+enum Command {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        // in milliseconds since the Unix epoch
+        expires_at: Option<u64>,
+    },
+    Remove { key: Vec<u8> },
+    /// Header of a batch, followed by its `len` commands.
+    Batch { len: u64 },
+}
+
+impl Command {
+    fn set(key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Command {
+        Command::Set {
+            key,
+            value,
+            expires_at,
+        }
+    }
+
+    fn remove(key: Vec<u8>) -> Command {
+        Command::Remove { key }
+    }
+}
+
+
+/// Command of the version 1 log format, before set commands had an expiry time.
+#[derive(Deserialize)]
+enum LegacyCommand {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+    Batch { len: u64 },
+}
+
+
+impl From<LegacyCommand> for Command {
+    fn from(cmd: LegacyCommand) -> Command {
+        match cmd {
+            LegacyCommand::Set { key, value } => Command::set(key, value, None),
+            LegacyCommand::Remove { key } => Command::remove(key),
+            LegacyCommand::Batch { len } => Command::Batch { len },
+        }
+    }
+}
+
+
+/// Reads the next command of a log file in the given format version.
+fn decode_command<R: Read>(reader: &mut R, version: u32) -> Result<Option<Command>> {
+    if version == 1 {
+        Ok(log_format::read_record::<_, LegacyCommand>(reader)?.map(Command::from))
+    } else {
+        log_format::read_record(reader)
+    }
+}
+
+
+/// Represents the position and length of a command record in the log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CommandPos {
+    gen: u64,
+    pos: u64,
+    len: u64,
+    // expiry time of the value set, in milliseconds since the Unix epoch
+    expires_at: Option<u64>,
+    // sequence number of the write, 0 for the commands loaded when the store
+    // is opened, see `Syncer`
+    seq: u64,
+}
+
+
+impl CommandPos {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+
+impl From<(u64, Range<u64>)> for CommandPos {
+    fn from((gen, range): (u64, Range<u64>)) -> Self {
+        CommandPos {
+            gen,
+            pos: range.start,
+            len: range.end - range.start,
+            expires_at: None,
+            seq: 0,
+        }
+    }
+}
+
+
+/// An open log file along with the format version of its records.
+struct LogReader {
+    reader: BufReaderWithPos<File>,
+    version: u32,
+}
+
+
+impl LogReader {
+    /// Wraps a log file whose header is not read yet.
+    fn new(file: File) -> Result<LogReader> {
+        Ok(LogReader {
+            reader: BufReaderWithPos::new(file)?,
+            version: log_format::LOG_VERSION,
+        })
+    }
+
+    /// Wraps a log file and reads its header.
+    fn open(file: File) -> Result<LogReader> {
+        let mut log_reader = LogReader::new(file)?;
+        log_reader.reader.seek(SeekFrom::Start(0))?;
+        if let Some(version) = log_format::read_header(&mut log_reader.reader)? {
+            log_reader.version = version;
+        }
+        Ok(log_reader)
+    }
+}
+
+
+struct BufReaderWithPos<R: Read + Seek> {
+    reader: BufReader<R>,
+    pos: u64,
+}
+
+impl<R: Read + Seek> BufReaderWithPos<R> {
+    fn new(mut inner: R) -> Result<Self> {
+        let pos = inner.stream_position()?;
+        Ok(BufReaderWithPos {
+            reader: BufReader::new(inner),
+            pos,
+        })
+    }
+}
+
+impl<R: Read + Seek> Read for BufReaderWithPos<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.reader.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+
+struct BufWriterWithPos<W: Write + Seek> {
+    writer: BufWriter<W>,
+    pos: u64,
+}
+
+impl<W: Write + Seek> BufWriterWithPos<W> {
+    fn new(mut inner: W) -> Result<Self> {
+        let pos = inner.stream_position()?;
+        Ok(BufWriterWithPos {
+            writer: BufWriter::new(inner),
+            pos,
+        })
+    }
+
+    fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
+}
+
+impl BufWriterWithPos<File> {
+    /// Drops everything written from `pos` on, buffered or not.
+    fn truncate(&mut self, pos: u64) -> Result<()> {
+        // the old buffer is discarded rather than written when dropped
+        let file = self.writer.get_ref().try_clone()?;
+        let (file, _) = mem::replace(&mut self.writer, BufWriter::new(file)).into_parts();
+        file.set_len(pos)?;
+        // the file is opened in append mode, so writes go on from its new end
+        self.pos = pos;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Write for BufWriterWithPos<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.writer.seek(pos)?;
+        Ok(self.pos)
+    }
+}
\ No newline at end of file
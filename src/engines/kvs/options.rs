@@ -0,0 +1,183 @@
+use std::time::Duration;
+
+use super::RecoveryMode;
+use crate::{KvsError, Result};
+
+
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const DEFAULT_FILE_DEAD_RATIO: f64 = 0.5;
+const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+
+/// When written data is forced to disk.
+///
+/// Whatever the policy, `KvsEngine::flush` syncs every write made so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Writes are only flushed to the OS, which writes them to disk later.
+    /// Acknowledged writes may be lost on power loss.
+    #[default]
+    Never,
+    /// Every write is synced to disk before it is applied and acknowledged, so
+    /// a write that fails to sync is never read.
+    ///
+    /// Concurrent writes are synced together, so one `sync_data` call covers
+    /// all the writers waiting for it. Writes that read the current values,
+    /// `remove`, `compare_and_swap` and transactions, first wait for the writes
+    /// still being synced.
+    Always,
+    /// Writes are synced once every given number of writes. At most that many
+    /// acknowledged writes may be lost on power loss.
+    EveryN(u64),
+    /// Writes are synced periodically by a background thread. The writes of
+    /// the last period may be lost on power loss.
+    Interval(Duration),
+}
+
+
+/// Options to configure how a `KvStore` is opened.
+///
+/// # Example
+///
+/// ```no_run
+/// # use kvs::{KvStore, KvStoreOptions, SyncPolicy};
+/// let options = KvStoreOptions::new()
+///     .compaction_threshold(16 * 1024 * 1024)
+///     .sync_policy(SyncPolicy::Always);
+/// let store = KvStore::open_with_options("data", &options)?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(super) compaction_threshold: u64,
+    pub(super) compaction_ratio: f64,
+    pub(super) file_dead_ratio: f64,
+    pub(super) max_file_size: Option<u64>,
+    pub(super) sync_policy: SyncPolicy,
+    pub(super) sweep_interval: Duration,
+    pub(super) read_only: bool,
+    pub(super) recovery_mode: RecoveryMode,
+}
+
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            compaction_ratio: 0.0,
+            file_dead_ratio: DEFAULT_FILE_DEAD_RATIO,
+            max_file_size: None,
+            sync_policy: SyncPolicy::default(),
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
+            read_only: false,
+            recovery_mode: RecoveryMode::default(),
+        }
+    }
+}
+
+
+impl KvStoreOptions {
+    /// Creates options with the defaults used by `KvStore::open`.
+    pub fn new() -> Self {
+        KvStoreOptions::default()
+    }
+
+    /// Sets how many bytes of stale commands trigger a compaction. Defaults to 1 MiB.
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Sets the share of stale bytes in the log, between 0 and 1, that must also be
+    /// reached to trigger a compaction. Defaults to 0, i.e. only the threshold counts.
+    pub fn compaction_ratio(mut self, ratio: f64) -> Self {
+        self.compaction_ratio = ratio;
+        self
+    }
+
+    /// Sets the share of garbage, expired values included, above which a compaction
+    /// rewrites a log file. Files below it are left untouched. Defaults to 0.5.
+    pub fn file_dead_ratio(mut self, ratio: f64) -> Self {
+        self.file_dead_ratio = ratio;
+        self
+    }
+
+    /// Sets the size above which the active log file is closed and a new one started.
+    /// Compactions split their output the same way. Log files are unbounded by default.
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = Some(bytes);
+        self
+    }
+
+    /// Sets when written data is forced to disk. Defaults to `SyncPolicy::Never`.
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Sets how often expired keys are looked for and their removal written.
+    /// Defaults to 1 second.
+    ///
+    /// Expired keys are never returned, whatever the interval.
+    pub fn sweep_interval(mut self, interval: Duration) -> Self {
+        self.sweep_interval = interval;
+        self
+    }
+
+    /// Opens the store without ever writing to its directory.
+    ///
+    /// No log file is created and no compaction runs. Every write, `set` and
+    /// `remove` included, fails with `KvsError::ReadOnly`, expired keys are hidden
+    /// but never removed, and damaged log records are skipped instead of being
+    /// repaired. Read-only stores may share a directory, but not with a store
+    /// opened for writing.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sets how damaged log records are handled. Defaults to `RecoveryMode::Truncate`.
+    pub fn recovery_mode(mut self, mode: RecoveryMode) -> Self {
+        self.recovery_mode = mode;
+        self
+    }
+
+    pub(super) fn validate(&self) -> Result<()> {
+        if !(0.0..=1.0).contains(&self.compaction_ratio) {
+            return Err(KvsError::StringError(format!(
+                "Invalid compaction ratio {}, it must be between 0 and 1",
+                self.compaction_ratio
+            )));
+        }
+        if !(0.0..1.0).contains(&self.file_dead_ratio) {
+            return Err(KvsError::StringError(format!(
+                "Invalid file dead ratio {}, it must be at least 0 and below 1",
+                self.file_dead_ratio
+            )));
+        }
+        match self.sync_policy {
+            SyncPolicy::EveryN(0) => {
+                return Err(KvsError::StringError(
+                    "The number of writes between syncs must be greater than zero".to_owned(),
+                ))
+            }
+            SyncPolicy::Interval(interval) if interval == Duration::from_secs(0) => {
+                return Err(KvsError::StringError(
+                    "The sync interval must be greater than zero".to_owned(),
+                ))
+            }
+            _ => {}
+        }
+        if self.sweep_interval == Duration::from_secs(0) {
+            return Err(KvsError::StringError(
+                "The sweep interval must be greater than zero".to_owned(),
+            ));
+        }
+        if self.max_file_size == Some(0) {
+            return Err(KvsError::StringError(
+                "The max log file size must be greater than zero".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
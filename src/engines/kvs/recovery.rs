@@ -0,0 +1,152 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use super::{log_path, KvStoreOptions};
+use crate::{KvsError, Result};
+
+
+/// What `KvStore::open` does when it finds a damaged record in a log file.
+///
+/// Records after a damaged one cannot be located, so recovery always stops
+/// at the last valid record of the generation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RecoveryMode {
+    /// Fails to open the store.
+    Strict,
+    /// Truncates the damaged bytes away if they end the newest log file, as a
+    /// write torn by a crash does. Damage in an older log file is not left by a
+    /// crash, so it is quarantined instead.
+    #[default]
+    Truncate,
+    /// Moves the damaged bytes to a `<gen>.corrupt` file next to the log.
+    Quarantine,
+}
+
+
+/// Bytes dropped from one generation during recovery.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Repair {
+    /// Generation of the damaged log file.
+    pub gen: u64,
+    /// Offset of the first damaged byte, the file is truncated here.
+    pub offset: u64,
+    /// Number of bytes dropped from the log file.
+    pub bytes_dropped: u64,
+    /// Why the bytes at `offset` could not be read.
+    pub reason: String,
+    /// The file holding the dropped bytes in `RecoveryMode::Quarantine`.
+    pub quarantined_to: Option<PathBuf>,
+}
+
+
+/// What recovery did while opening a `KvStore`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Repairs in generation order, empty if no damage was found.
+    pub repairs: Vec<Repair>,
+}
+
+
+impl RepairReport {
+    /// Returns true if nothing had to be dropped.
+    pub fn is_clean(&self) -> bool {
+        self.repairs.is_empty()
+    }
+
+    /// Total number of bytes dropped from all generations.
+    pub fn bytes_dropped(&self) -> u64 {
+        self.repairs.iter().map(|repair| repair.bytes_dropped).sum()
+    }
+}
+
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "generation {}: dropped {} bytes at offset {} ({})",
+            self.gen, self.bytes_dropped, self.offset, self.reason
+        )?;
+        if let Some(path) = &self.quarantined_to {
+            write!(f, ", saved to {:?}", path)?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Deals with the damaged bytes of generation `gen` starting at `offset` according
+/// to the recovery mode. `newest` tells if `gen` is the newest generation, the
+/// only one a crash can leave a torn write in.
+///
+/// In read-only mode the file is left untouched and the bytes are only skipped.
+pub(super) fn repair(
+    path: &Path,
+    gen: u64,
+    offset: u64,
+    reason: String,
+    newest: bool,
+    options: &KvStoreOptions,
+) -> Result<Repair> {
+    let file_path = log_path(path, gen);
+    let mode = match options.recovery_mode {
+        RecoveryMode::Truncate if !newest => RecoveryMode::Quarantine,
+        mode => mode,
+    };
+    if mode == RecoveryMode::Strict {
+        return Err(KvsError::Corruption(format!(
+            "{:?} at offset {}: {}",
+            file_path, offset, reason
+        )));
+    }
+
+    if options.read_only {
+        let repair = Repair {
+            gen,
+            offset,
+            bytes_dropped: fs::metadata(&file_path)?.len() - offset,
+            reason,
+            quarantined_to: None,
+        };
+        warn!("{:?} is corrupted, skipping {}", file_path, repair);
+        return Ok(repair);
+    }
+
+    let mut file = OpenOptions::new().read(true).write(true).open(&file_path)?;
+    let len = file.metadata()?.len();
+    let quarantined_to = if mode == RecoveryMode::Quarantine {
+        let corrupt_path = corrupt_path(path, gen);
+        file.seek(SeekFrom::Start(offset))?;
+        let mut corrupt = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&corrupt_path)?;
+        io::copy(&mut (&mut file).take(len - offset), &mut corrupt)?;
+        corrupt.sync_all()?;
+        Some(corrupt_path)
+    } else {
+        None
+    };
+    file.set_len(offset)?;
+    file.sync_all()?;
+
+    let repair = Repair {
+        gen,
+        offset,
+        bytes_dropped: len - offset,
+        reason,
+        quarantined_to,
+    };
+    warn!("{:?} is corrupted, {}", file_path, repair);
+    Ok(repair)
+}
+
+
+fn corrupt_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.corrupt", gen))
+}
+
@@ -0,0 +1,299 @@
+//! Snapshots of a `KvStore`, reading the keys as of a sequence number.
+//!
+//! The index only holds the latest version of each key. While snapshots are
+//! open, every write first records the version it replaces in the history,
+//! tagged with its own sequence number, so that a snapshot finds the version a
+//! key had when it was taken. The history is trimmed as snapshots are dropped.
+//!
+//! Compactions would remove the log files holding these older versions, so
+//! the removal of the files compacted while snapshots are open is deferred
+//! until those snapshots are dropped.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crossbeam_skiplist::SkipMap;
+
+use super::compaction::remove_compacted;
+use super::{read_value, Command, CommandPos, DirLock, Index, KvStore, KvStoreReader};
+use crate::engines::{EntryIter, KvsSnapshot, ScanIter, ScanOptions};
+use crate::Result;
+
+
+/// The snapshots open on a store and what they need kept.
+#[derive(Default)]
+pub(super) struct Snapshots {
+    state: Mutex<SnapshotState>,
+    // older versions of the keys written while snapshots are open
+    history: SkipMap<Vec<u8>, Mutex<Vec<Version>>>,
+}
+
+
+#[derive(Default)]
+struct SnapshotState {
+    next_id: u64,
+    // sequence number of each open snapshot, by id
+    open: BTreeMap<u64, u64>,
+    // keys with versions in the history, by sequence number of the write that
+    // replaced them, so that `close` only visits the versions it drops
+    replaced: BTreeMap<u64, Vec<Vec<u8>>>,
+    // generations compacted while snapshots were open, to remove once every
+    // snapshot with an id below the bound is dropped
+    pending: Vec<(u64, Vec<u64>)>,
+}
+
+
+/// A version of a key replaced by a write.
+struct Version {
+    // sequence number of the write that replaced it
+    replaced_at: u64,
+    // `None` if the key did not exist
+    cmd_pos: Option<CommandPos>,
+}
+
+
+impl Snapshots {
+    /// Registers a snapshot of the writes up to `seq`. Returns its id.
+    ///
+    /// Must be called with the writer lock held, if any, so that no write with a
+    /// lower sequence number is applied afterwards.
+    fn open(&self, seq: u64) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.open.insert(id, seq);
+        id
+    }
+
+    /// Unregisters a snapshot. Returns the compacted generations no snapshot needs anymore.
+    fn close(&self, id: u64) -> Vec<u64> {
+        let mut state = self.state.lock().unwrap();
+        state.open.remove(&id);
+        // a version is needed by the snapshots taken before it was replaced
+        let oldest_seq = state.open.values().min().copied().unwrap_or(u64::MAX);
+        let needed = match oldest_seq.checked_add(1) {
+            Some(next_seq) => state.replaced.split_off(&next_seq),
+            None => BTreeMap::new(),
+        };
+        let released = mem::replace(&mut state.replaced, needed);
+        for key in released.into_values().flatten() {
+            if let Some(entry) = self.history.get(&key) {
+                let mut versions = entry.value().lock().unwrap();
+                versions.retain(|version| version.replaced_at > oldest_seq);
+                if versions.is_empty() {
+                    entry.remove();
+                }
+            }
+        }
+        let oldest_id = state.open.keys().next().copied().unwrap_or(u64::MAX);
+        let (released, pending) = state
+            .pending
+            .drain(..)
+            .partition(|(bound, _)| *bound <= oldest_id);
+        state.pending = pending;
+        released.into_iter().flat_map(|(_, gens)| gens).collect()
+    }
+
+    /// Records the version of the key `cmd` replaces, if snapshots are open.
+    ///
+    /// Must be called with the writer lock held, before `cmd` is applied to the index.
+    pub(super) fn record(&self, index: &Index, cmd: &Command, seq: u64) {
+        let key = match cmd {
+            Command::Set { key, .. } | Command::Remove { key } => key,
+            Command::Batch { .. } => return,
+        };
+        // held until the version is recorded, so that `close` cannot miss it
+        let mut state = self.state.lock().unwrap();
+        if state.open.is_empty() {
+            return;
+        }
+        let version = Version {
+            replaced_at: seq,
+            cmd_pos: index.get(key).map(|entry| entry.value().load()),
+        };
+        let entry = self.history.get_or_insert_with(key.clone(), Default::default);
+        entry.value().lock().unwrap().push(version);
+        state.replaced.entry(seq).or_default().push(key.clone());
+    }
+
+    /// Defers the removal of the compacted generations `gens` if snapshots are open.
+    ///
+    /// Returns false if they can be removed right away.
+    pub(super) fn defer_removal(&self, gens: &[u64]) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.open.is_empty() {
+            return false;
+        }
+        let bound = state.next_id;
+        state.pending.push((bound, gens.to_vec()));
+        true
+    }
+
+    /// Returns true if `key` was written after the write `seq`, which must be the
+    /// sequence number of a snapshot still open.
+    pub(super) fn written_since(&self, key: &[u8], seq: u64) -> bool {
+        self.history.get(key).is_some_and(|entry| {
+            let versions = entry.value().lock().unwrap();
+            versions.iter().any(|version| version.replaced_at > seq)
+        })
+    }
+
+    /// Returns the position of the value `key` had after the write `seq`, or
+    /// `None` if it did not exist.
+    fn version_at(&self, index: &Index, key: &[u8], seq: u64) -> Option<CommandPos> {
+        // versions are recorded before the index is updated, so a newer entry
+        // always comes with the version it replaced
+        match index.get(key).map(|entry| entry.value().load()) {
+            Some(cmd_pos) if cmd_pos.seq <= seq => Some(cmd_pos),
+            _ => self.history.get(key).and_then(|entry| {
+                let versions = entry.value().lock().unwrap();
+                versions
+                    .iter()
+                    .find(|version| version.replaced_at > seq)
+                    .and_then(|version| version.cmd_pos)
+            }),
+        }
+    }
+}
+
+
+/// A read-only view of a `KvStore`, see `KvsEngine::snapshot`.
+///
+/// It reads the index as of its sequence number, and keeps the log files it
+/// may read from being removed until it is dropped. It holds the lock on the
+/// directory, so no other store can open it until then, even once the store
+/// the snapshot was taken from is dropped.
+#[derive(Clone)]
+pub struct KvStoreSnapshot {
+    index: Arc<Index>,
+    reader: KvStoreReader,
+    registration: Arc<Registration>,
+}
+
+
+/// Unregisters the snapshot once every clone of it is dropped.
+struct Registration {
+    id: u64,
+    seq: u64,
+    snapshots: Arc<Snapshots>,
+    path: Arc<PathBuf>,
+    epoch: Arc<AtomicU64>,
+    // the files whose removal is deferred may be in use by the next store opened
+    // on the directory, so it stays locked until they are removed
+    _lock: Option<Arc<DirLock>>,
+}
+
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let gens = self.snapshots.close(self.id);
+        if !gens.is_empty() {
+            remove_compacted(&self.path, &gens);
+            self.epoch.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+
+impl KvStoreSnapshot {
+    /// Takes a snapshot of the writes to `store` up to `seq`, see `Snapshots::open`.
+    pub(super) fn new(seq: u64, store: &KvStore) -> KvStoreSnapshot {
+        let registration = Registration {
+            id: store.snapshots.open(seq),
+            seq,
+            snapshots: Arc::clone(&store.snapshots),
+            path: Arc::clone(&store.reader.path),
+            epoch: Arc::clone(&store.reader.epoch),
+            _lock: store._lock.clone(),
+        };
+        KvStoreSnapshot {
+            index: Arc::clone(&store.index),
+            reader: store.reader.clone(),
+            registration: Arc::new(registration),
+        }
+    }
+
+    /// Returns the sequence number of the last write the snapshot sees.
+    pub(super) fn seq(&self) -> u64 {
+        self.registration.seq
+    }
+
+    pub(super) fn snapshots(&self) -> &Snapshots {
+        &self.registration.snapshots
+    }
+
+    fn version_at(&self, key: &[u8]) -> Option<CommandPos> {
+        let registration = &self.registration;
+        registration
+            .snapshots
+            .version_at(&self.index, key, registration.seq)
+    }
+
+    /// Returns the version of each key in the range, in key order.
+    fn versions_in(&self, range: (Bound<&[u8]>, Bound<&[u8]>)) -> Vec<(Vec<u8>, CommandPos)> {
+        // keys removed since the snapshot was taken are only in the history
+        let keys: BTreeSet<Vec<u8>> = self
+            .index
+            .range::<[u8], _>(range)
+            .map(|entry| entry.key().clone())
+            .chain(
+                self.registration
+                    .snapshots
+                    .history
+                    .range::<[u8], _>(range)
+                    .map(|entry| entry.key().clone()),
+            )
+            .collect();
+        keys.into_iter()
+            .filter_map(|key| self.version_at(&key).map(|cmd_pos| (key, cmd_pos)))
+            .collect()
+    }
+}
+
+
+impl KvsSnapshot for KvStoreSnapshot {
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        match self.version_at(key) {
+            Some(cmd_pos) => read_value(&self.reader, cmd_pos, || self.version_at(key)),
+            None => Ok(None),
+        }
+    }
+
+    /// The versions of the keys are looked up when the scan starts, their values
+    /// are read as the iterator advances.
+    fn scan<K, R>(&self, range: R, options: ScanOptions) -> Result<ScanIter>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let range: (Bound<&[u8]>, Bound<&[u8]>) = (
+            range.start_bound().map(AsRef::as_ref),
+            range.end_bound().map(AsRef::as_ref),
+        );
+        let entries = self.versions_in(range);
+        let snapshot = self.clone();
+        let pairs = options
+            .ordered(entries.into_iter())
+            .filter_map(move |(key, cmd_pos)| {
+                read_value(&snapshot.reader, cmd_pos, || snapshot.version_at(&key))
+                    .transpose()
+                    .map(|value| value.map(|value| (key, value)))
+            });
+        Ok(Box::new(options.limited(pairs)))
+    }
+
+    fn entries(&self) -> Result<EntryIter> {
+        let entries = self.versions_in((Bound::Unbounded, Bound::Unbounded));
+        let snapshot = self.clone();
+        Ok(Box::new(entries.into_iter().filter_map(move |(key, cmd_pos)| {
+            read_value(&snapshot.reader, cmd_pos, || snapshot.version_at(&key))
+                .transpose()
+                .map(|value| value.map(|value| (key, value, cmd_pos.expires_at)))
+        })))
+    }
+}
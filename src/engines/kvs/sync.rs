@@ -0,0 +1,159 @@
+use std::fs::File;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, error};
+
+use super::SyncPolicy;
+use crate::Result;
+
+
+/// Forces written commands to disk according to the `SyncPolicy`.
+///
+/// Every command appended to the log gets a sequence number. Writers wait for
+/// their sequence number to be synced after releasing the writer lock, so
+/// concurrent writers share a single `sync_data` call (group commit): the first
+/// one to wait syncs everything appended so far while the others wait for it.
+///
+/// With `SyncPolicy::Always`, writes are only applied to the index once synced,
+/// see `KvStoreWriter::settle`.
+pub(super) struct Syncer {
+    policy: SyncPolicy,
+    state: Mutex<SyncState>,
+    synced: Condvar,
+}
+
+
+struct SyncState {
+    // the active log file
+    file: Arc<File>,
+    // sequence number of the last command appended
+    appended: u64,
+    // sequence number of the last command synced to disk
+    synced: u64,
+    // whether a writer is syncing on behalf of the others
+    syncing: bool,
+}
+
+
+impl Syncer {
+    pub(super) fn new(policy: SyncPolicy, file: File) -> Result<Arc<Syncer>> {
+        let syncer = Arc::new(Syncer {
+            policy,
+            state: Mutex::new(SyncState {
+                file: Arc::new(file),
+                appended: 0,
+                synced: 0,
+                syncing: false,
+            }),
+            synced: Condvar::new(),
+        });
+        if let SyncPolicy::Interval(interval) = policy {
+            let syncer = Arc::downgrade(&syncer);
+            thread::Builder::new()
+                .name("kvs-sync".to_owned())
+                .spawn(move || sync_periodically(syncer, interval))?;
+        }
+        Ok(syncer)
+    }
+
+    /// Records that a command was appended and flushed to the active log file.
+    ///
+    /// Must be called with the writer lock held. Returns the sequence number of the command.
+    pub(super) fn appended(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.appended += 1;
+        state.appended
+    }
+
+    pub(super) fn policy(&self) -> SyncPolicy {
+        self.policy
+    }
+
+    /// Returns the sequence number of the last command synced to disk.
+    pub(super) fn synced(&self) -> u64 {
+        self.state.lock().unwrap().synced
+    }
+
+    /// Makes `file` the active log file once every command in the previous one is synced.
+    ///
+    /// Must be called with the writer lock held.
+    pub(super) fn switch_file(&self, file: File) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if self.policy != SyncPolicy::Never && state.synced < state.appended {
+            state.file.sync_data()?;
+            state.synced = state.appended;
+            self.synced.notify_all();
+        }
+        state.file = Arc::new(file);
+        Ok(())
+    }
+
+    /// Waits until the command with sequence number `seq` is durable as required
+    /// by the policy.
+    ///
+    /// Must be called without the writer lock held, so that other writers can
+    /// append and join the same sync.
+    pub(super) fn commit(&self, seq: u64) -> Result<()> {
+        match self.policy {
+            SyncPolicy::Always => self.sync_upto(seq),
+            SyncPolicy::EveryN(n) => {
+                let synced = self.state.lock().unwrap().synced;
+                if seq >= synced + n {
+                    self.sync_upto(seq)
+                } else {
+                    Ok(())
+                }
+            }
+            SyncPolicy::Interval(_) | SyncPolicy::Never => Ok(()),
+        }
+    }
+
+    /// Syncs every command appended so far, whatever the policy.
+    pub(super) fn sync_all(&self) -> Result<()> {
+        let appended = self.state.lock().unwrap().appended;
+        self.sync_upto(appended)
+    }
+
+    /// Syncs every command up to the sequence number `seq`, or waits for the
+    /// thread syncing them.
+    pub(super) fn sync_upto(&self, seq: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        while state.synced < seq {
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+            // become the leader and sync on behalf of everyone waiting
+            state.syncing = true;
+            let target = state.appended;
+            let file = Arc::clone(&state.file);
+            drop(state);
+            let res = file.sync_data();
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if res.is_ok() {
+                state.synced = state.synced.max(target);
+            }
+            self.synced.notify_all();
+            res?;
+        }
+        Ok(())
+    }
+}
+
+
+fn sync_periodically(syncer: Weak<Syncer>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let syncer = match syncer.upgrade() {
+            Some(syncer) => syncer,
+            None => break,
+        };
+        if let Err(e) = syncer.sync_all() {
+            error!("Periodic sync failed: {}", e);
+        }
+    }
+    debug!("Sync thread exits because the store is dropped.");
+}
@@ -0,0 +1,161 @@
+//! Offline checks of a store directory, see `KvStore::verify`.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::path::Path;
+
+use crossbeam_skiplist::SkipMap;
+
+use super::compaction::LogStats;
+use super::{
+    apply_command, hint, legacy, log_format, log_path, replay, sorted_gen_list, Command, CommandPos,
+    DirLock, LogReader, Repair,
+};
+use crate::engines::ttl::now_millis;
+use crate::{KvsError, Result};
+
+
+/// What `KvStore::verify` found in a store directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// The log files, in generation order.
+    pub generations: Vec<GenerationReport>,
+    /// Number of keys with a value.
+    pub keys: u64,
+    /// Number of keys whose value has expired, their removal not being written yet.
+    pub expired_keys: u64,
+}
+
+
+/// What `KvStore::verify` found in one log file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenerationReport {
+    /// Generation of the log file.
+    pub gen: u64,
+    /// Size of the log file, in bytes.
+    pub size: u64,
+    /// Bytes of the values read from this file, and of the removals hiding values
+    /// of older files.
+    pub live_bytes: u64,
+    /// Bytes of the commands that are stale, the file header and damaged bytes excluded.
+    pub garbage_bytes: u64,
+    /// Number of keys whose value is in this file, expired ones excluded.
+    pub keys: u64,
+    /// Whether a hint file matching the log was found.
+    pub hint: bool,
+    /// Whether the file was compacted, as listed in the hint files of a newer one,
+    /// and holds nothing needed. Such files are left by compactions whose input
+    /// files could not be removed, e.g. because the store was closed while
+    /// snapshots were open.
+    pub orphaned: bool,
+    /// Whether the file is in the JSON format of the first versions, which
+    /// `KvStore::open` rewrites in the current format.
+    pub needs_upgrade: bool,
+    /// The first damaged record, `KvStore::open` dropping it and everything after it.
+    pub corruption: Option<Repair>,
+}
+
+
+impl VerifyReport {
+    /// Returns true if no log file is damaged.
+    pub fn is_clean(&self) -> bool {
+        self.generations.iter().all(|gen| gen.corruption.is_none())
+    }
+}
+
+
+/// Reads the store in `path` the way `KvStore::open` does, without writing anything.
+pub(super) fn verify(path: &Path) -> Result<VerifyReport> {
+    if !path.is_dir() {
+        return Err(KvsError::KvPathNotFoundError);
+    }
+    // a store writing the directory would make the report inconsistent
+    let _lock = DirLock::acquire_shared(path)?;
+
+    let index = SkipMap::new();
+    // the command positions are all that is needed, the stats are left aside
+    let mut stats = LogStats::default();
+    // the oldest generation setting each key, and the latest removal of each key
+    let mut first_sets: HashMap<Vec<u8>, u64> = HashMap::new();
+    let mut removals: HashMap<Vec<u8>, CommandPos> = HashMap::new();
+    let mut generations = BTreeMap::new();
+    // the generations that compactions read, as listed in the hint files
+    let mut compacted: HashSet<u64> = HashSet::new();
+    for gen in sorted_gen_list(path)? {
+        let file = File::open(log_path(path, gen))?;
+        let size = file.metadata()?.len();
+        let needs_upgrade = legacy::is_json_log(path, gen)?;
+        let apply = |cmd: Command, cmd_pos: CommandPos| {
+            match &cmd {
+                Command::Set { key, .. } => {
+                    first_sets.entry(key.clone()).or_insert(gen);
+                }
+                Command::Remove { key } => {
+                    removals.insert(key.clone(), cmd_pos);
+                }
+                Command::Batch { .. } => {}
+            }
+            apply_command(&index, cmd, cmd_pos, &mut stats)
+        };
+        let damage = if needs_upgrade {
+            legacy::replay(path, gen, apply)?
+        } else {
+            replay(gen, &mut LogReader::new(file)?, apply)?
+        };
+        let hint = hint::compacted(path, gen, size);
+        // compactions only read older generations
+        compacted.extend(hint.iter().flatten().filter(|&&input| input < gen));
+        let corruption = damage.map(|(offset, reason)| Repair {
+            gen,
+            offset,
+            bytes_dropped: size - offset,
+            reason,
+            quarantined_to: None,
+        });
+        let report = GenerationReport {
+            gen,
+            size,
+            live_bytes: 0,
+            garbage_bytes: 0,
+            keys: 0,
+            hint: hint.is_some(),
+            orphaned: false,
+            needs_upgrade,
+            corruption,
+        };
+        generations.insert(gen, report);
+    }
+
+    let mut report = VerifyReport::default();
+    let now = now_millis();
+    for entry in index.iter() {
+        let cmd_pos = entry.value().load();
+        if cmd_pos.is_expired(now) {
+            report.expired_keys += 1;
+            continue;
+        }
+        report.keys += 1;
+        // the index only points to the generations just read
+        let gen = generations.get_mut(&cmd_pos.gen).unwrap();
+        gen.live_bytes += cmd_pos.len;
+        gen.keys += 1;
+    }
+    for (key, cmd_pos) in removals {
+        // the key is still removed, and would be found in an older file otherwise
+        let needed = !index.contains_key(&key)
+            && first_sets.get(&key).is_some_and(|&gen| gen < cmd_pos.gen);
+        if needed {
+            generations.get_mut(&cmd_pos.gen).unwrap().live_bytes += cmd_pos.len;
+        }
+    }
+
+    for gen in generations.values_mut() {
+        let readable = gen.corruption.as_ref().map_or(gen.size, |repair| repair.offset);
+        let header_len = if gen.needs_upgrade { 0 } else { log_format::HEADER_LEN };
+        gen.garbage_bytes = readable.saturating_sub(header_len + gen.live_bytes);
+        // files emptied by later writes are only waiting for the next compaction
+        gen.orphaned = gen.live_bytes == 0 && compacted.contains(&gen.gen);
+    }
+    report.generations = generations.into_values().collect();
+    Ok(report)
+}
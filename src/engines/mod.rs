@@ -1,30 +1,196 @@
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
 use crate::Result;
 
 
+mod batch;
+mod dump;
 mod kvs;
+mod scan;
 mod sled;
+mod snapshot;
+mod transaction;
+mod ttl;
 
-pub use self::kvs::KvStore;
-pub use self::sled::SledKvsEngine;
+pub use self::batch::WriteBatch;
+pub(crate) use self::batch::BatchOp;
+pub use self::dump::{dump, restore};
+pub use self::kvs::{
+    DirLock, GenerationReport, KvStore, KvStoreOptions, KvStoreSnapshot, RecoveryMode, Repair,
+    RepairReport, SyncPolicy, VerifyReport,
+};
+pub use self::scan::{ScanIter, ScanOptions};
+pub use self::sled::{SledKvsEngine, SledSnapshot};
+pub use self::snapshot::{EntryIter, KvsSnapshot};
+pub use self::transaction::Transaction;
+pub(crate) use self::transaction::MAX_TRANSACTION_ATTEMPTS;
 
 
+/// A key-value storage engine. Keys and values are arbitrary bytes.
+///
+/// Keys and values written are taken as anything convertible into `Vec<u8>`, keys
+/// only looked up as anything viewable as `[u8]`, so strings can be passed as they
+/// are and reads need no allocation. `get_string` decodes values as UTF-8.
 pub trait KvsEngine: Clone + Send + 'static {
-    /// Sets the value of a string key to a string.
+    /// The read-only view returned by `snapshot`.
+    type Snapshot: KvsSnapshot;
+
+    /// Sets the value of a key.
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&self, key: String, value: String) -> Result<()>;
+    fn set<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(&self, key: K, value: V) -> Result<()>;
 
-    /// Gets the string value of a given string key.
+    /// Sets the value of a key, for the given duration only.
+    ///
+    /// Once expired, the key behaves as if it were removed.
+    fn set_with_ttl<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(
+        &self,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> Result<()>;
+
+    /// Gets the value of a given key.
     ///
     /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Result<Option<String>>;
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>>;
+
+    /// Gets the value of a given key as a string.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if the value is not valid UTF-8.
+    fn get_string<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<String>> {
+        Ok(self.get(key)?.map(String::from_utf8).transpose()?)
+    }
 
     /// Removes a given key.
     ///
     /// # Errors
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove<K: AsRef<[u8]>>(&self, key: K) -> Result<()>;
+
+    /// Sets the value of a key to `new`, or removes the key if `new` is `None`,
+    /// only if its current value is `expected`. `None` stands for a missing key.
+    ///
+    /// The comparison and the write are atomic. Returns whether the write was made.
+    fn compare_and_swap<K: Into<Vec<u8>>>(
+        &self,
+        key: K,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool>;
+
+    /// Sets the value of a key only if the key does not exist.
+    ///
+    /// Returns whether the value was set.
+    fn set_if_absent<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(&self, key: K, value: V) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value.into()))
+    }
+
+    /// Applies every write of the batch, in order.
+    ///
+    /// The batch is all-or-nothing: if the process crashes midway, none of its
+    /// writes are visible once the store is reopened.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Returns the key/value pairs whose keys are in the given range, in key order.
+    ///
+    /// As with `sled::Tree::range`, the key type of an unbounded range must be
+    /// spelled out, e.g. `scan::<&[u8], _>(.., options)`.
+    fn scan<K, R>(&self, range: R, options: ScanOptions) -> Result<ScanIter>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>;
+
+    /// Returns the key/value pairs whose keys start with the given prefix, in key order.
+    fn scan_prefix<P: AsRef<[u8]>>(&self, prefix: P, options: ScanOptions) -> Result<ScanIter> {
+        let (start, end) = scan::prefix_range(prefix.as_ref());
+        self.scan::<&[u8], _>((start, end.as_ref().map(Vec::as_slice)), options)
+    }
+
+    /// Runs `f` in a transaction and returns its result.
+    ///
+    /// The writes made through the transaction are applied atomically once `f`
+    /// returns, and dropped if it fails. If a key read or written by `f` is written
+    /// concurrently in the meantime, the writes are dropped and `f` is run again,
+    /// as with `sled::Tree::transaction`, so it should have no other side effect.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Conflict` if the writes still conflict after `f` was
+    /// run 100 times.
+    fn transaction<F, T>(&self, f: F) -> Result<T>
+    where
+        F: Fn(&mut Transaction<'_>) -> Result<T>;
+
+    /// Returns a read-only view of the engine as it is now, which later writes
+    /// leave unchanged.
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+
+    /// Writes a copy of the engine's data, as it is now, to the new directory
+    /// `dest`, which the engine can then be opened from. Reads and writes go on
+    /// meanwhile.
+    ///
+    /// The engine is recorded in `dest` the way `kvs-server` records it, so the
+    /// server can be started on the checkpoint.
+    fn checkpoint<P: AsRef<Path>>(&self, dest: P) -> Result<()>;
+
+    /// Flushes buffered writes to disk.
+    fn flush(&self) -> Result<()>;
+}
+
+
+/// A storage engine, as named in the `engine` file of a data directory.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Engine {
+    Kvs,
+    Sled,
+}
+
+
+impl Engine {
+    /// The names of the engines, as written in `engine` files.
+    pub const NAMES: &'static [&'static str] = &["kvs", "sled"];
+}
+
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "kvs" => Ok(Engine::Kvs),
+            "sled" => Ok(Engine::Sled),
+            _ => Err(format!("valid values: {}", Engine::NAMES.join(", "))),
+        }
+    }
 }
 
 
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Engine::Kvs => "kvs",
+            Engine::Sled => "sled",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+
+/// Writes the `engine` file naming the engine of the data directory `dir`, so
+/// that `kvs-server` can be started on it.
+pub fn write_engine_file(dir: &Path, engine: Engine) -> Result<()> {
+    let mut file = File::create(dir.join("engine"))?;
+    file.write_all(engine.to_string().as_bytes())?;
+    file.sync_data()?;
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use std::iter::Take;
+use std::ops::Bound;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+
+/// Iterator over the key/value pairs found by a scan.
+pub type ScanIter = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>>;
+
+
+/// Options of a range or prefix scan.
+///
+/// # Example
+///
+/// ```no_run
+/// # use kvs::{KvStore, KvsEngine, ScanOptions};
+/// let store = KvStore::open("data")?;
+/// // the last 10 keys starting with "user/"
+/// let options = ScanOptions::new().limit(10).reverse(true);
+/// for pair in store.scan_prefix("user/", options)? {
+///     let (key, value) = pair?;
+///     println!("{:?} {:?}", key, value);
+/// }
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanOptions {
+    pub(crate) limit: Option<usize>,
+    pub(crate) reverse: bool,
+}
+
+
+impl ScanOptions {
+    /// Creates options scanning every matching key in ascending order.
+    pub fn new() -> Self {
+        ScanOptions::default()
+    }
+
+    /// Sets the maximum number of pairs returned.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Returns the pairs in descending key order if `reverse` is true.
+    pub fn reverse(mut self, reverse: bool) -> Self {
+        self.reverse = reverse;
+        self
+    }
+
+    /// Applies the order to an iterator in ascending key order.
+    pub(crate) fn ordered<'a, I>(&self, iter: I) -> Box<dyn Iterator<Item = I::Item> + 'a>
+    where
+        I: DoubleEndedIterator + 'a,
+    {
+        if self.reverse {
+            Box::new(iter.rev())
+        } else {
+            Box::new(iter)
+        }
+    }
+
+    /// Applies the limit to an iterator over the pairs returned, so after the
+    /// removed and expired keys are skipped.
+    pub(crate) fn limited<I: Iterator>(&self, iter: I) -> Take<I> {
+        iter.take(self.limit.unwrap_or(usize::MAX))
+    }
+}
+
+
+/// Returns the range of the keys starting with `prefix`.
+pub(crate) fn prefix_range(prefix: &[u8]) -> (Bound<&[u8]>, Bound<Vec<u8>>) {
+    // the smallest key greater than every key starting with the prefix: the
+    // prefix with its last byte below 0xff incremented and the bytes after it cut
+    let mut end = prefix.to_vec();
+    let end = loop {
+        match end.pop() {
+            Some(0xff) => continue,
+            Some(byte) => {
+                end.push(byte + 1);
+                break Bound::Excluded(end);
+            }
+            None => break Bound::Unbounded,
+        }
+    };
+    (Bound::Included(prefix), end)
+}
@@ -0,0 +1,437 @@
+use super::ttl::{expiry_time, now_millis};
+use super::{write_engine_file, Engine};
+use super::{
+    BatchOp, KvsEngine, KvsSnapshot, ScanIter, ScanOptions, Transaction, WriteBatch, MAX_TRANSACTION_ATTEMPTS,
+};
+use crate::{KvsError, Result};
+use crossbeam::channel::{self, RecvTimeoutError, Sender};
+use log::{debug, error};
+use sled::transaction::{ConflictableTransactionError, TransactionError, TransactionResult, Transactional};
+use sled::{Batch, Db, IVec, Tree};
+use std::fs::{self, File};
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::{Arc, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use self::snapshot::Snapshots;
+
+mod snapshot;
+
+pub use self::snapshot::SledSnapshot;
+
+
+/// Tree holding the expiry time of the keys set with a TTL, in big-endian
+/// milliseconds since the Unix epoch.
+const EXPIRY_TREE: &str = "kvs-expiry";
+
+
+/// How often the sweeper of `SledKvsEngine::new` looks for expired keys.
+const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+
+/// Wrapper of `sled::Db`
+///
+/// As `KvStore`, it removes expired keys in a background thread, so that they
+/// stop taking up disk space even if they are never read again.
+#[derive(Clone)]
+pub struct SledKvsEngine {
+    db: Db,
+    expiry: Tree,
+    // serializes the writes, which record the versions they replace for the
+    // open snapshots
+    snapshots: Arc<Snapshots>,
+    // stops the sweeper once the last clone of the engine is dropped
+    _sweeper: Option<Arc<Sweeper>>,
+}
+
+
+impl SledKvsEngine {
+    /// Creates a `SledKvsEngine` from `sled::Db`, removing expired keys every second.
+    pub fn new(db: Db) -> Result<Self> {
+        SledKvsEngine::with_sweep_interval(db, DEFAULT_SWEEP_INTERVAL)
+    }
+
+    /// Creates a `SledKvsEngine` from `sled::Db`, removing expired keys at the
+    /// given interval.
+    pub fn with_sweep_interval(db: Db, interval: Duration) -> Result<Self> {
+        let expiry = db.open_tree(EXPIRY_TREE)?;
+        let snapshots = Arc::new(Snapshots::default());
+        let sweeper = Sweeper::spawn(db.clone(), expiry.clone(), Arc::clone(&snapshots), interval)?;
+        Ok(SledKvsEngine {
+            db,
+            expiry,
+            snapshots,
+            _sweeper: Some(Arc::new(sweeper)),
+        })
+    }
+
+    /// Creates a `SledKvsEngine` from `sled::Db` without removing expired keys in
+    /// the background, e.g. to copy the data of a database without changing it.
+    ///
+    /// Expired keys are still hidden from reads, and removed when `get` finds them.
+    pub fn without_sweeper(db: Db) -> Result<Self> {
+        Ok(SledKvsEngine {
+            expiry: db.open_tree(EXPIRY_TREE)?,
+            db,
+            snapshots: Arc::new(Snapshots::default()),
+            _sweeper: None,
+        })
+    }
+
+    /// Sets a value expiring at the given time, or never if `expires_at` is `None`.
+    fn set_with_expiry(&self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let tree: &Tree = &self.db;
+        write(tree, &self.expiry, &self.snapshots, &[&key], || {
+            tx_result((tree, &self.expiry).transaction(|(tree, expiry)| {
+                tree.insert(key.as_slice(), value.as_slice())?;
+                match expires_at {
+                    Some(expires_at) => expiry.insert(key.as_slice(), &expires_at.to_be_bytes())?,
+                    None => expiry.remove(key.as_slice())?,
+                };
+                Ok(())
+            }))
+        })?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    /// Applies the batch as the write `last_seq` locks, see `write_locked`, or as
+    /// the next write if `None`.
+    fn write_batch_locked(&self, last_seq: Option<MutexGuard<'_, u64>>, batch: WriteBatch) -> Result<()> {
+        let tree: &Tree = &self.db;
+        let mut keys = Vec::with_capacity(batch.len());
+        let mut sled_batch = Batch::default();
+        // every key written loses its expiry time
+        let mut expiry_batch = Batch::default();
+        for op in batch.ops {
+            match op {
+                BatchOp::Set { key, value } => {
+                    expiry_batch.remove(key.as_slice());
+                    sled_batch.insert(key.as_slice(), value);
+                    keys.push(key);
+                }
+                BatchOp::Remove { key } => {
+                    expiry_batch.remove(key.as_slice());
+                    sled_batch.remove(key.as_slice());
+                    keys.push(key);
+                }
+            }
+        }
+        let keys: Vec<&[u8]> = keys.iter().map(Vec::as_slice).collect();
+        let apply = || {
+            tx_result((tree, &self.expiry).transaction(|(tree, expiry)| {
+                tree.apply_batch(&sled_batch)?;
+                expiry.apply_batch(&expiry_batch)?;
+                Ok(())
+            }))
+        };
+        match last_seq {
+            Some(last_seq) => write_locked(tree, &self.expiry, &self.snapshots, last_seq, &keys, apply)?,
+            None => write(tree, &self.expiry, &self.snapshots, &keys, apply)?,
+        }
+        tree.flush()?;
+        Ok(())
+    }
+}
+
+
+impl KvsEngine for SledKvsEngine {
+    type Snapshot = SledSnapshot;
+
+    fn set<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(&self, key: K, value: V) -> Result<()> {
+        self.set_with_expiry(key.into(), value.into(), None)
+    }
+
+    fn set_with_ttl<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(
+        &self,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.set_with_expiry(key.into(), value.into(), Some(expiry_time(ttl)))
+    }
+
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        let tree: &Tree = &self.db;
+        let value = match tree.get(key)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        if is_expired(self.expiry.get(key)?, now_millis()) {
+            purge(tree, &self.expiry, &self.snapshots, key)?;
+            return Ok(None);
+        }
+        Ok(Some(value.to_vec()))
+    }
+
+    fn remove<K: AsRef<[u8]>>(&self, key: K) -> Result<()> {
+        let key = key.as_ref();
+        let tree: &Tree = &self.db;
+        let now = now_millis();
+        write(tree, &self.expiry, &self.snapshots, &[key], || {
+            tx_result((tree, &self.expiry).transaction(|(tree, expiry)| {
+                let value = tree.remove(key)?;
+                let expires_at = expiry.remove(key)?;
+                if value.is_none() || is_expired(expires_at, now) {
+                    return Err(ConflictableTransactionError::Abort(KvsError::KeyNotFoundError));
+                }
+                Ok(())
+            }))
+        })?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    fn compare_and_swap<K: Into<Vec<u8>>>(
+        &self,
+        key: K,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let key = key.into();
+        let tree: &Tree = &self.db;
+        let now = now_millis();
+        let swapped = write(tree, &self.expiry, &self.snapshots, &[&key], || {
+            tx_result((tree, &self.expiry).transaction(|(tree, expiry)| {
+                let current = match tree.get(key.as_slice())? {
+                    Some(_) if is_expired(expiry.get(key.as_slice())?, now) => None,
+                    current => current,
+                };
+                if current.as_deref() != expected.as_deref() {
+                    return Ok(false);
+                }
+                match &new {
+                    Some(value) => tree.insert(key.as_slice(), value.as_slice())?,
+                    None => tree.remove(key.as_slice())?,
+                };
+                expiry.remove(key.as_slice())?;
+                Ok(true)
+            }))
+        })?;
+        if swapped {
+            tree.flush()?;
+        }
+        Ok(swapped)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.write_batch_locked(None, batch)
+    }
+
+    fn scan<K, R>(&self, range: R, options: ScanOptions) -> Result<ScanIter>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let tree: &Tree = &self.db;
+        let expiry = self.expiry.clone();
+        let now = now_millis();
+        let pairs = options
+            .ordered(tree.range(range))
+            .map(move |pair| {
+                let (key, value) = pair?;
+                if is_expired(expiry.get(&key)?, now) {
+                    return Ok(None);
+                }
+                Ok(Some((key.to_vec(), value.to_vec())))
+            })
+            .filter_map(Result::transpose);
+        Ok(Box::new(options.limited(pairs)))
+    }
+
+    /// The transaction reads from a snapshot. Its writes are applied as a batch,
+    /// unless one of the keys it read or wrote was written since the snapshot.
+    fn transaction<F, T>(&self, f: F) -> Result<T>
+    where
+        F: Fn(&mut Transaction<'_>) -> Result<T>,
+    {
+        for _ in 0..MAX_TRANSACTION_ATTEMPTS {
+            let snapshot = self.snapshot()?;
+            let mut txn = Transaction::new(Box::new(|key| snapshot.get(key)));
+            let res = f(&mut txn)?;
+            let (keys, batch) = txn.into_parts();
+            if batch.is_empty() {
+                // the snapshot made the reads consistent already
+                return Ok(res);
+            }
+            let last_seq = self.snapshots.lock_writes();
+            if keys.iter().any(|key| snapshot.written_since(key)) {
+                continue;
+            }
+            self.write_batch_locked(Some(last_seq), batch)?;
+            return Ok(res);
+        }
+        Err(KvsError::Conflict)
+    }
+
+    /// Taking a snapshot only waits for the write in progress, if any. Writes
+    /// made while snapshots are open keep the versions they replace in memory,
+    /// until the snapshots that may read them are dropped.
+    fn snapshot(&self) -> Result<SledSnapshot> {
+        Ok(SledSnapshot::new(&self.db, &self.expiry, &self.snapshots))
+    }
+
+    /// Copies the key/value pairs of a snapshot to a new sled database, writes
+    /// going on meanwhile, and records the engine in its directory.
+    fn checkpoint<P: AsRef<Path>>(&self, dest: P) -> Result<()> {
+        let dest = dest.as_ref();
+        fs::create_dir(dest)?;
+        let snapshot = self.snapshot()?;
+        let db = sled::open(dest)?;
+        let expiry = db.open_tree(EXPIRY_TREE)?;
+        for entry in snapshot.entries_in(Bound::Unbounded, Bound::Unbounded, false) {
+            let (key, value, expires_at) = entry?;
+            if let Some(expires_at) = expires_at {
+                expiry.insert(key.as_slice(), expires_at)?;
+            }
+            db.insert(key, value)?;
+        }
+        db.flush()?;
+        write_engine_file(dest, Engine::Sled)?;
+        File::open(dest)?.sync_all()?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+
+/// Runs `apply`, which writes `keys`, as the next write.
+///
+/// While snapshots are open, writes are serialized, so that each one records
+/// the versions it replaces before it is applied, see `Snapshots`.
+fn write<T, F>(tree: &Tree, expiry: &Tree, snapshots: &Snapshots, keys: &[&[u8]], apply: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T>,
+{
+    if let Some(_write) = snapshots.unlocked_write() {
+        return apply();
+    }
+    write_locked(tree, expiry, snapshots, snapshots.lock_writes(), keys, apply)
+}
+
+
+/// Runs `apply`, which writes `keys`, as the write `last_seq` locks.
+fn write_locked<T, F>(
+    tree: &Tree,
+    expiry: &Tree,
+    snapshots: &Snapshots,
+    mut last_seq: MutexGuard<'_, u64>,
+    keys: &[&[u8]],
+    apply: F,
+) -> Result<T>
+where
+    F: FnOnce() -> Result<T>,
+{
+    let seq = *last_seq + 1;
+    for key in keys {
+        snapshots.record(tree, expiry, key, seq)?;
+    }
+    // the versions recorded for a failed write are the current ones, so
+    // snapshots read the same through them
+    *last_seq = seq;
+    apply()
+}
+
+
+/// Removes `key` if it has expired, in case it was not set again meanwhile.
+fn purge(tree: &Tree, expiry: &Tree, snapshots: &Snapshots, key: &[u8]) -> Result<()> {
+    let now = now_millis();
+    write(tree, expiry, snapshots, &[key], || {
+        tx_result((tree, expiry).transaction(|(tree, expiry)| {
+            if is_expired(expiry.get(key)?, now) {
+                tree.remove(key)?;
+                expiry.remove(key)?;
+            }
+            Ok(())
+        }))
+    })
+}
+
+
+/// Handle to the background thread removing expired keys, see `KvStore`'s.
+struct Sweeper {
+    // dropping it stops the thread
+    tx: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+
+impl Sweeper {
+    fn spawn(db: Db, expiry: Tree, snapshots: Arc<Snapshots>, interval: Duration) -> Result<Sweeper> {
+        let (tx, rx) = channel::bounded(0);
+        let handle = thread::Builder::new()
+            .name("sled-sweeper".to_owned())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                    if let Err(e) = sweep(&db, &expiry, &snapshots) {
+                        error!("Removing expired keys failed: {}", e);
+                    }
+                }
+                debug!("Sweeper thread exits because the engine is dropped.");
+            })?;
+        Ok(Sweeper {
+            tx: Some(tx),
+            handle: Some(handle),
+        })
+    }
+}
+
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("The sweeper thread panicked");
+            }
+        }
+    }
+}
+
+
+/// Removes every expired key.
+fn sweep(tree: &Tree, expiry: &Tree, snapshots: &Snapshots) -> Result<()> {
+    let now = now_millis();
+    let mut expired = Vec::new();
+    for pair in expiry.iter() {
+        let (key, expires_at) = pair?;
+        if is_expired(Some(expires_at), now) {
+            expired.push(key);
+        }
+    }
+    if !expired.is_empty() {
+        debug!("Removing {} expired keys", expired.len());
+    }
+    for key in expired {
+        purge(tree, expiry, snapshots, &key)?;
+    }
+    Ok(())
+}
+
+
+fn is_expired(expires_at: Option<IVec>, now: u64) -> bool {
+    expires_at.is_some_and(|expires_at| expiry_millis(expires_at) <= now)
+}
+
+
+/// Decodes an expiry time of the expiry tree.
+fn expiry_millis(expires_at: IVec) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&expires_at);
+    u64::from_be_bytes(bytes)
+}
+
+
+fn tx_result<T>(res: TransactionResult<T, KvsError>) -> Result<T> {
+    res.map_err(|e| match e {
+        TransactionError::Abort(e) => e,
+        TransactionError::Storage(e) => e.into(),
+    })
+}
@@ -0,0 +1,369 @@
+//! Snapshots of a `SledKvsEngine`, reading the keys as of a sequence number.
+//!
+//! The trees only hold the latest version of each key. While snapshots are
+//! open, every write first records the versions it replaces in the history,
+//! tagged with its own sequence number, so that a snapshot finds the version a
+//! key had when it was taken. The history is trimmed as snapshots are dropped.
+//!
+//! Writes are only serialized while snapshots are open. Otherwise they go on
+//! concurrently, and a snapshot being taken waits for them to finish.
+
+use std::collections::BTreeMap;
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use crossbeam_skiplist::SkipMap;
+use sled::{IVec, Tree};
+
+use super::{expiry_millis, is_expired};
+use crate::engines::ttl::now_millis;
+use crate::engines::{EntryIter, KvsSnapshot, ScanIter, ScanOptions};
+use crate::Result;
+
+
+/// The value of a key and its expiry time, `None` if the key does not exist.
+type KeyState = Option<(IVec, Option<IVec>)>;
+
+
+/// The snapshots open on an engine and the versions they need kept.
+#[derive(Default)]
+pub(super) struct Snapshots {
+    // sequence number of the last write, locked while a write is applied
+    last_seq: Mutex<u64>,
+    // number of snapshots open or being taken
+    open_count: AtomicUsize,
+    // number of writes going on without the lock, see `unlocked_write`
+    unlocked_writes: AtomicUsize,
+    state: Mutex<SnapshotState>,
+    // older versions of the keys written while snapshots are open
+    history: SkipMap<Vec<u8>, Mutex<Vec<Version>>>,
+}
+
+
+#[derive(Default)]
+struct SnapshotState {
+    next_id: u64,
+    // sequence number of each open snapshot, by id
+    open: BTreeMap<u64, u64>,
+    // keys with versions in the history, by sequence number of the write that
+    // replaced them, so that `close` only visits the versions it drops
+    replaced: BTreeMap<u64, Vec<Vec<u8>>>,
+}
+
+
+/// A version of a key replaced by a write.
+struct Version {
+    // sequence number of the write that replaced it
+    replaced_at: u64,
+    state: KeyState,
+}
+
+
+impl Snapshots {
+    /// Locks the writes, returning the sequence number of the last one.
+    pub(super) fn lock_writes(&self) -> MutexGuard<'_, u64> {
+        self.last_seq.lock().unwrap()
+    }
+
+    /// Lets a write go on without locking the writes if no snapshot is open.
+    ///
+    /// The write must be applied before the returned guard is dropped, and
+    /// locked with `lock_writes` instead if `None` is returned.
+    pub(super) fn unlocked_write(&self) -> Option<UnlockedWrite<'_>> {
+        // both counters are checked in the opposite order by `open`, so either
+        // the write sees the snapshot, or the snapshot waits for the write
+        self.unlocked_writes.fetch_add(1, Ordering::SeqCst);
+        let write = UnlockedWrite(self);
+        if self.open_count.load(Ordering::SeqCst) > 0 {
+            // dropping the guard lets the snapshot go on
+            return None;
+        }
+        Some(write)
+    }
+
+    /// Registers a snapshot of the writes applied so far. Returns its id and the
+    /// sequence number of the last write.
+    fn open(&self) -> (u64, u64) {
+        self.open_count.fetch_add(1, Ordering::SeqCst);
+        // the writes started without seeing the snapshot record no versions
+        while self.unlocked_writes.load(Ordering::SeqCst) > 0 {
+            thread::yield_now();
+        }
+        // no write with a lower sequence number is applied afterwards
+        let last_seq = self.lock_writes();
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.open.insert(id, *last_seq);
+        (id, *last_seq)
+    }
+
+    /// Unregisters a snapshot, dropping the versions no snapshot needs anymore.
+    fn close(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.open.remove(&id);
+        self.open_count.fetch_sub(1, Ordering::SeqCst);
+        // a version is needed by the snapshots taken before it was replaced
+        let oldest_seq = state.open.values().min().copied().unwrap_or(u64::MAX);
+        let needed = match oldest_seq.checked_add(1) {
+            Some(next_seq) => state.replaced.split_off(&next_seq),
+            None => BTreeMap::new(),
+        };
+        let released = mem::replace(&mut state.replaced, needed);
+        for key in released.into_values().flatten() {
+            if let Some(entry) = self.history.get(&key) {
+                let mut versions = entry.value().lock().unwrap();
+                versions.retain(|version| version.replaced_at > oldest_seq);
+                if versions.is_empty() {
+                    entry.remove();
+                }
+            }
+        }
+    }
+
+    /// Records the current version of `key`, if snapshots are open.
+    ///
+    /// Must be called with the writes locked, before the write `seq` is applied.
+    pub(super) fn record(&self, tree: &Tree, expiry: &Tree, key: &[u8], seq: u64) -> Result<()> {
+        // held until the version is recorded, so that `close` cannot miss it
+        let mut state = self.state.lock().unwrap();
+        if state.open.is_empty() {
+            return Ok(());
+        }
+        let key_state = match tree.get(key)? {
+            Some(value) => Some((value, expiry.get(key)?)),
+            None => None,
+        };
+        let version = Version {
+            replaced_at: seq,
+            state: key_state,
+        };
+        let entry = self.history.get_or_insert_with(key.to_vec(), Default::default);
+        entry.value().lock().unwrap().push(version);
+        state.replaced.entry(seq).or_default().push(key.to_vec());
+        Ok(())
+    }
+
+    /// Returns true if `key` was written after the write `seq`, which must be the
+    /// sequence number of a snapshot still open.
+    fn written_since(&self, key: &[u8], seq: u64) -> bool {
+        self.history.get(key).is_some_and(|entry| {
+            let versions = entry.value().lock().unwrap();
+            versions.iter().any(|version| version.replaced_at > seq)
+        })
+    }
+
+    /// Returns the version of `key` replaced by the first write after the write
+    /// `seq`, if there was one.
+    fn replaced_state(&self, key: &[u8], seq: u64) -> Option<KeyState> {
+        self.history.get(key).and_then(|entry| {
+            let versions = entry.value().lock().unwrap();
+            versions
+                .iter()
+                .find(|version| version.replaced_at > seq)
+                .map(|version| version.state.clone())
+        })
+    }
+}
+
+
+/// A write going on without the lock, see `Snapshots::unlocked_write`.
+pub(super) struct UnlockedWrite<'a>(&'a Snapshots);
+
+
+impl Drop for UnlockedWrite<'_> {
+    fn drop(&mut self) {
+        self.0.unlocked_writes.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+
+/// A read-only view of a `SledKvsEngine`, see `KvsEngine::snapshot`.
+///
+/// It reads the trees of the engine, falling back on the history for the keys
+/// written since it was taken.
+#[derive(Clone)]
+pub struct SledSnapshot {
+    tree: Tree,
+    expiry: Tree,
+    registration: Arc<Registration>,
+}
+
+
+/// Unregisters the snapshot once every clone of it is dropped.
+struct Registration {
+    id: u64,
+    seq: u64,
+    snapshots: Arc<Snapshots>,
+}
+
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.snapshots.close(self.id);
+    }
+}
+
+
+impl SledSnapshot {
+    /// Takes a snapshot of the writes applied so far.
+    pub(super) fn new(tree: &Tree, expiry: &Tree, snapshots: &Arc<Snapshots>) -> SledSnapshot {
+        let (id, seq) = snapshots.open();
+        let registration = Registration {
+            id,
+            seq,
+            snapshots: Arc::clone(snapshots),
+        };
+        SledSnapshot {
+            tree: tree.clone(),
+            expiry: expiry.clone(),
+            registration: Arc::new(registration),
+        }
+    }
+
+    /// Returns true if `key` was written since the snapshot was taken.
+    pub(super) fn written_since(&self, key: &[u8]) -> bool {
+        let registration = &self.registration;
+        registration.snapshots.written_since(key, registration.seq)
+    }
+
+    /// Returns the key/value pairs whose keys are in the range, with the expiry
+    /// time of their keys, expired or not.
+    pub(super) fn entries_in(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>, reverse: bool) -> SnapshotRange {
+        SnapshotRange {
+            snapshot: self.clone(),
+            start,
+            end,
+            reverse,
+        }
+    }
+
+    fn state_at(&self, key: &[u8]) -> Result<KeyState> {
+        // versions are recorded before the trees are updated, so if a newer
+        // version is read here, the version it replaced is in the history
+        let live = match self.tree.get(key)? {
+            Some(value) => Some((value, self.expiry.get(key)?)),
+            None => None,
+        };
+        let registration = &self.registration;
+        Ok(registration
+            .snapshots
+            .replaced_state(key, registration.seq)
+            .unwrap_or(live))
+    }
+}
+
+
+impl KvsSnapshot for SledSnapshot {
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        Ok(match self.state_at(key.as_ref())? {
+            Some((value, expires_at)) if !is_expired(expires_at.clone(), now_millis()) => Some(value.to_vec()),
+            _ => None,
+        })
+    }
+
+    /// The keys are looked up as the iterator advances.
+    fn scan<K, R>(&self, range: R, options: ScanOptions) -> Result<ScanIter>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let start = range.start_bound().map(|key| key.as_ref().to_vec());
+        let end = range.end_bound().map(|key| key.as_ref().to_vec());
+        let now = now_millis();
+        let pairs = self
+            .entries_in(start, end, options.reverse)
+            .filter_map(move |entry| match entry {
+                Ok((key, value, expires_at)) => {
+                    if is_expired(expires_at, now) {
+                        None
+                    } else {
+                        Some(Ok((key, value.to_vec())))
+                    }
+                }
+                Err(e) => Some(Err(e)),
+            });
+        Ok(Box::new(options.limited(pairs)))
+    }
+
+    fn entries(&self) -> Result<EntryIter> {
+        let now = now_millis();
+        let entries = self
+            .entries_in(Bound::Unbounded, Bound::Unbounded, false)
+            .filter_map(move |entry| match entry {
+                Ok((key, value, expires_at)) => {
+                    if is_expired(expires_at.clone(), now) {
+                        None
+                    } else {
+                        Some(Ok((key, value.to_vec(), expires_at.map(expiry_millis))))
+                    }
+                }
+                Err(e) => Some(Err(e)),
+            });
+        Ok(Box::new(entries))
+    }
+}
+
+
+/// Iterator over the keys of a snapshot in a range, see `SledSnapshot::entries_in`.
+///
+/// Keys removed since the snapshot was taken are only in the history, so each
+/// step takes the next key of either the tree or the history.
+pub(super) struct SnapshotRange {
+    snapshot: SledSnapshot,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    reverse: bool,
+}
+
+
+impl Iterator for SnapshotRange {
+    type Item = Result<(Vec<u8>, IVec, Option<IVec>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = {
+                let bounds = (self.start.as_ref(), self.end.as_ref());
+                let bounds = (bounds.0.map(Vec::as_slice), bounds.1.map(Vec::as_slice));
+                let mut tree_range = self.snapshot.tree.range::<&[u8], _>(bounds);
+                let tree_key = if self.reverse {
+                    tree_range.next_back()
+                } else {
+                    tree_range.next()
+                };
+                let tree_key = match tree_key.transpose() {
+                    Ok(pair) => pair.map(|(key, _)| key.to_vec()),
+                    Err(e) => return Some(Err(e.into())),
+                };
+                let history = &self.snapshot.registration.snapshots.history;
+                let mut history_range = history.range::<[u8], _>(bounds);
+                let history_key = if self.reverse {
+                    history_range.next_back()
+                } else {
+                    history_range.next()
+                };
+                let history_key = history_key.map(|entry| entry.key().clone());
+                match (tree_key, history_key) {
+                    (Some(a), Some(b)) if self.reverse => a.max(b),
+                    (Some(a), Some(b)) => a.min(b),
+                    (Some(key), None) | (None, Some(key)) => key,
+                    (None, None) => return None,
+                }
+            };
+            if self.reverse {
+                self.end = Bound::Excluded(key.clone());
+            } else {
+                self.start = Bound::Excluded(key.clone());
+            }
+            match self.snapshot.state_at(&key) {
+                Ok(Some((value, expires_at))) => return Some(Ok((key, value, expires_at))),
+                // the key did not exist when the snapshot was taken
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::ops::RangeBounds;
+
+use super::scan::{self, ScanIter, ScanOptions};
+use crate::Result;
+
+
+/// Iterator over the entries of a snapshot: each key, its value and the expiry
+/// time of the key, in milliseconds since the Unix epoch.
+pub type EntryIter = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>, Option<u64>)>>>;
+
+
+/// A read-only view of an engine as it was when `KvsEngine::snapshot` was called.
+///
+/// Writes made afterwards are not visible through the snapshot, so reading several
+/// keys from it gives a consistent result. Values set with a TTL still expire.
+///
+/// # Example
+///
+/// ```no_run
+/// # use kvs::{KvStore, KvsEngine, KvsSnapshot};
+/// let store = KvStore::open("data")?;
+/// let snapshot = store.snapshot()?;
+/// store.set("account/alice", "0")?;
+/// // the balance before the write
+/// let balance = snapshot.get_string("account/alice")?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+pub trait KvsSnapshot: Clone + Send + 'static {
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>>;
+
+    /// Gets the value of a given key as a string.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if the value is not valid UTF-8.
+    fn get_string<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<String>> {
+        Ok(self.get(key)?.map(String::from_utf8).transpose()?)
+    }
+
+    /// Returns the key/value pairs whose keys are in the given range, in key order.
+    fn scan<K, R>(&self, range: R, options: ScanOptions) -> Result<ScanIter>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>;
+
+    /// Returns the key/value pairs whose keys start with the given prefix, in key order.
+    fn scan_prefix<P: AsRef<[u8]>>(&self, prefix: P, options: ScanOptions) -> Result<ScanIter> {
+        let (start, end) = scan::prefix_range(prefix.as_ref());
+        self.scan::<&[u8], _>((start, end.as_ref().map(Vec::as_slice)), options)
+    }
+
+    /// Returns every entry, along with the expiry time of its key, in key order.
+    ///
+    /// Expired keys are left out, as in scans.
+    fn entries(&self) -> Result<EntryIter>;
+}
@@ -0,0 +1,96 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::WriteBatch;
+use crate::Result;
+
+
+/// Number of times a transaction is run before giving up on conflicts.
+pub(crate) const MAX_TRANSACTION_ATTEMPTS: usize = 100;
+
+
+/// Reads a key as of the start of a transaction.
+pub(crate) type TransactionRead<'a> = Box<dyn Fn(&[u8]) -> Result<Option<Vec<u8>>> + 'a>;
+
+
+/// A transaction run by `KvsEngine::transaction`.
+///
+/// Reads see the engine as it was when the transaction started, along with the
+/// transaction's own writes. Writes are buffered and applied atomically once
+/// the closure returns.
+///
+/// # Example
+///
+/// ```no_run
+/// # use kvs::{KvStore, KvsEngine};
+/// let store = KvStore::open("data")?;
+/// store.transaction(|txn| {
+///     let count: u64 = txn.get_string("counter")?.map_or(0, |count| count.parse().unwrap());
+///     txn.set("counter", (count + 1).to_string())
+/// })?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+pub struct Transaction<'a> {
+    read: TransactionRead<'a>,
+    // keys read from the engine, a concurrent write to one of them is a conflict
+    reads: BTreeSet<Vec<u8>>,
+    // the latest write of each key, `None` for a removal
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(read: TransactionRead<'a>) -> Self {
+        Transaction {
+            read,
+            reads: BTreeSet::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    pub fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        self.reads.insert(key.to_vec());
+        (self.read)(key)
+    }
+
+    /// Gets the value of a given key as a string.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if the value is not valid UTF-8.
+    pub fn get_string<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<String>> {
+        Ok(self.get(key)?.map(String::from_utf8).transpose()?)
+    }
+
+    /// Sets the value of a key when the transaction commits.
+    pub fn set<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(&mut self, key: K, value: V) -> Result<()> {
+        self.writes.insert(key.into(), Some(value.into()));
+        Ok(())
+    }
+
+    /// Removes a key when the transaction commits. Removing a missing key does nothing.
+    pub fn remove<K: Into<Vec<u8>>>(&mut self, key: K) -> Result<()> {
+        self.writes.insert(key.into(), None);
+        Ok(())
+    }
+
+    /// Returns the keys read or written, and the writes to apply.
+    pub(crate) fn into_parts(self) -> (BTreeSet<Vec<u8>>, WriteBatch) {
+        let mut keys = self.reads;
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            keys.insert(key.clone());
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            }
+        }
+        (keys, batch)
+    }
+}
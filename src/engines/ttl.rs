@@ -0,0 +1,18 @@
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+
+/// Returns the current time in milliseconds since the Unix epoch, the unit of
+/// the expiry times stored by the engines.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+
+/// Returns the expiry time of a value written now with the given time to live.
+pub(crate) fn expiry_time(ttl: Duration) -> u64 {
+    let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+    now_millis().saturating_add(ttl)
+}
@@ -1,6 +1,6 @@
 
 use std::fmt;
-use serde_json;
+use std::path::PathBuf;
 use std::string::FromUtf8Error;
 
 // Define a generic alias for a `Result` with the error type `ParseIntError`.
@@ -16,13 +16,25 @@ pub enum KvsError {
     IoError,
     SerdeError,
     StringError(String),
+    /// A log file is damaged, e.g. by a torn write.
+    Corruption(String),
+    /// A log file is not in a format this version can read.
+    UnsupportedLogFormat(String),
+    /// The store is opened read-only.
+    ReadOnly,
+    /// A transaction conflicts with a concurrent write.
+    Conflict,
+    /// The data directory is locked by another store.
+    DirectoryInUse(PathBuf),
+    /// A write failed and could not be undone, the store must be reopened.
+    WriterPoisoned,
     Sled(sled::Error),
     Utf8(FromUtf8Error)
 }
 
 impl fmt::Display for KvsError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match &*self {
+        match self {
             KvsError::KeyNotFound =>
                 write!(f, "Key not found"),
             KvsError::UnexpectedCommandType =>
@@ -34,9 +46,21 @@ impl fmt::Display for KvsError {
             KvsError::IoError => 
                 write!(f, "IO error"),
             KvsError::SerdeError =>
-                write!(f, "Serde json error"),
+                write!(f, "Serialization error"),
             KvsError::StringError(s) =>
                 write!(f, "{}", s),
+            KvsError::Corruption(reason) =>
+                write!(f, "Corrupted log: {}", reason),
+            KvsError::UnsupportedLogFormat(reason) =>
+                write!(f, "Unsupported log format: {}", reason),
+            KvsError::ReadOnly =>
+                write!(f, "The store is opened read-only"),
+            KvsError::Conflict =>
+                write!(f, "The transaction conflicts with a concurrent write"),
+            KvsError::DirectoryInUse(path) =>
+                write!(f, "The directory {:?} is used by another store", path),
+            KvsError::WriterPoisoned =>
+                write!(f, "A failed write could not be undone, the store must be reopened"),
             KvsError::Sled(e) =>
                 write!(f, "sled error: {}", e),
             KvsError::Utf8(e) => 
@@ -57,8 +81,8 @@ impl From<FromUtf8Error> for KvsError {
     }
 }
 
-impl From<serde_json::Error> for KvsError {
-    fn from(_err: serde_json::Error) -> KvsError {
+impl From<bincode::Error> for KvsError {
+    fn from(_err: bincode::Error) -> KvsError {
         KvsError::SerdeError
     }
 }
@@ -9,6 +9,11 @@ pub mod thread_pool;
 
 pub use thread_pool::*;
 pub use client::KvsClient;
-pub use server::KvsServer;
-pub use engines::{KvsEngine, KvStore, SledKvsEngine};
+pub use server::{KvsServer, ShutdownHandle};
+pub use engines::{
+    dump, restore, write_engine_file, DirLock, Engine, EntryIter, GenerationReport, KvsEngine,
+    KvsSnapshot, KvStore, KvStoreOptions, KvStoreSnapshot, RecoveryMode, Repair, RepairReport,
+    ScanIter, ScanOptions, SledKvsEngine, SledSnapshot, SyncPolicy, Transaction, VerifyReport,
+    WriteBatch,
+};
 pub use error::{KvsError, Result};
\ No newline at end of file
@@ -1,63 +1,336 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
+use crate::common::{
+    read_message, BatchResponse, CasResponse, CheckpointResponse, GetResponse, RemoveResponse, Request, ScanResponse, SetResponse,
+    TransactionResponse, SCAN_CHUNK_SIZE,
+};
 use crate::thread_pool::ThreadPool;
-use crate::{KvsEngine, Result};
-use log::{debug, error};
-use serde_json::Deserializer;
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use crate::{KvsEngine, KvsError, KvsSnapshot, Result, ScanIter};
+use log::{debug, error, info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::Bound;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_TRANSACTION_TIMEOUT: Duration = Duration::from_secs(60);
 
 
 pub struct KvsServer<E : KvsEngine, P: ThreadPool> {
     engine: E,
     pool : P,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    transaction_timeout: Duration,
+    // checkpoints requested by clients are written under it, refused if `None`
+    checkpoint_dir: Option<PathBuf>,
+    connections: Arc<Connections>,
 }
 
 
 impl<E : KvsEngine, P : ThreadPool> KvsServer<E, P> {
     /// Create a `KvsServer` with a given storage engine.
     pub fn new(engine: E, pool : P) -> Self {
-        KvsServer { engine, pool }
+        KvsServer {
+            engine,
+            pool,
+            shutdown: ShutdownHandle::new(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            transaction_timeout: DEFAULT_TRANSACTION_TIMEOUT,
+            checkpoint_dir: None,
+            connections: Arc::new(Connections::default()),
+        }
+    }
+
+    /// Sets how long `run` waits for in-flight requests once a shutdown is requested.
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Sets how long a client may keep a transaction open. Once it expires, the
+    /// transaction is rolled back and the connection closed, so that an idle
+    /// client does not keep the engine from dropping older versions of the keys.
+    /// Defaults to one minute.
+    pub fn with_transaction_timeout(mut self, timeout: Duration) -> Self {
+        self.transaction_timeout = timeout;
+        self
+    }
+
+    /// Sets the directory the checkpoints requested by clients are written under.
+    ///
+    /// Clients name a new directory by a path relative to it, without `..`.
+    /// Checkpoint requests are refused unless this is set.
+    pub fn with_checkpoint_dir<D: Into<PathBuf>>(mut self, dir: D) -> Self {
+        self.checkpoint_dir = Some(dir.into());
+        self
+    }
+
+    /// Returns a handle which stops the server when `shutdown` is called on it.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
     }
 
     /// Run the server listening on the given address
-    pub fn run<A: ToSocketAddrs>(mut self, addr: A) -> Result<()> {
+    ///
+    /// Returns once a shutdown is requested through a `ShutdownHandle`, after the
+    /// in-flight requests finish (or the shutdown timeout expires) and the engine is flushed.
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
         let listener = TcpListener::bind(addr)?;
+        if !self.shutdown.listening_on(listener.local_addr()?) {
+            return self.drain();
+        }
+
         for stream in listener.incoming() {
+            if self.shutdown.is_requested() {
+                break;
+            }
             let engine = self.engine.clone();
-            self.pool.spawn(move || match stream {
+            let checkpoint_dir = self.checkpoint_dir.clone();
+            let transaction_timeout = self.transaction_timeout;
+            match stream {
                 Ok(stream) => {
-                    if let Err(e) = serve(engine, stream) {
-                        error!("Error on serving client: {}", e);
-                    }
+                    let conn = match self.connections.register(&stream) {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            error!("Connection failed: {}", e);
+                            continue;
+                        }
+                    };
+                    self.pool.spawn(move || {
+                        let _conn = conn;
+                        let res = serve(engine, stream, checkpoint_dir.as_deref(), transaction_timeout);
+                        if let Err(e) = res {
+                            error!("Error on serving client: {}", e);
+                        }
+                    })
                 }
                 Err(e) => error!("Connection failed: {}", e),
-            })
+            }
         }
-        Ok(())
+        self.drain()
+    }
+
+    /// Lets in-flight requests finish and flushes the engine.
+    fn drain(&self) -> Result<()> {
+        info!("Shutting down");
+        let remaining = self.connections.close(self.shutdown_timeout);
+        if remaining > 0 {
+            warn!("{} connections still open after the shutdown timeout", remaining);
+        }
+        self.engine.flush()
+    }
+}
+
+
+/// A handle used to stop a running `KvsServer`.
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<Mutex<ShutdownState>>);
+
+
+#[derive(Default)]
+struct ShutdownState {
+    requested: bool,
+    // address of the listener to wake up, known once the server is running
+    addr: Option<SocketAddr>,
+}
+
+
+impl ShutdownHandle {
+    fn new() -> Self {
+        ShutdownHandle(Arc::new(Mutex::new(ShutdownState::default())))
+    }
+
+    /// Stops the server from accepting connections and makes its `run` return.
+    pub fn shutdown(&self) {
+        let addr = {
+            let mut state = self.0.lock().unwrap();
+            state.requested = true;
+            state.addr.take()
+        };
+        // `accept` blocks until the next connection, so we connect to wake it up
+        if let Some(addr) = addr {
+            if let Err(e) = TcpStream::connect(addr) {
+                error!("Cannot wake up the listener on {}: {}", addr, e);
+            }
+        }
+    }
+
+    fn is_requested(&self) -> bool {
+        self.0.lock().unwrap().requested
+    }
+
+    /// Records the listening address, returns false if a shutdown was already requested.
+    fn listening_on(&self, mut addr: SocketAddr) -> bool {
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        let mut state = self.0.lock().unwrap();
+        state.addr = Some(addr);
+        !state.requested
+    }
+}
+
+
+/// Open client connections, tracked so that shutdown can wait for them.
+#[derive(Default)]
+struct Connections {
+    streams: Mutex<HashMap<u64, TcpStream>>,
+    next_id: Mutex<u64>,
+    closed: Condvar,
+}
+
+
+impl Connections {
+    fn register(self: &Arc<Self>, stream: &TcpStream) -> Result<ConnectionGuard> {
+        let id = {
+            let mut next_id = self.next_id.lock().unwrap();
+            *next_id += 1;
+            *next_id
+        };
+        self.streams.lock().unwrap().insert(id, stream.try_clone()?);
+        Ok(ConnectionGuard {
+            id,
+            connections: Arc::clone(self),
+        })
+    }
+
+    /// Stops reading new requests from every connection and waits until they are
+    /// all closed or the timeout expires.
+    ///
+    /// Returns the number of connections still open.
+    fn close(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut streams = self.streams.lock().unwrap();
+        for stream in streams.values() {
+            // a request being served still gets its response written back
+            if let Err(e) = stream.shutdown(Shutdown::Read) {
+                debug!("Cannot shut down connection: {}", e);
+            }
+        }
+        while !streams.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            streams = self.closed.wait_timeout(streams, deadline - now).unwrap().0;
+        }
+        streams.len()
+    }
+}
+
+
+/// Unregisters the connection when dropped, even if serving it panicked.
+struct ConnectionGuard {
+    id: u64,
+    connections: Arc<Connections>,
+}
+
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.streams.lock().unwrap().remove(&self.id);
+        self.connections.closed.notify_all();
     }
 }
 
 
-fn serve<E: KvsEngine>(engine: E, tcp : TcpStream) -> Result<()> {
+fn serve<E: KvsEngine>(
+    engine: E,
+    tcp : TcpStream,
+    checkpoint_dir: Option<&Path>,
+    transaction_timeout: Duration,
+) -> Result<()> {
     let peer_addr = tcp.peer_addr()?;
-    let reader = BufReader::new(&tcp);
+    let mut reader = BufReader::new(&tcp);
     let mut writer = BufWriter::new(&tcp);
-    let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
 
     macro_rules! send_resp {
         ($resp:expr) => {{
             let resp = $resp;
-            serde_json::to_writer(&mut writer, &resp)?;
+            bincode::serialize_into(&mut writer, &resp)?;
             writer.flush()?;
             debug!("Response sent to {}: {:?}", peer_addr, resp);
         }};
     }
 
-    for req in req_reader {
-        let req = req?;
+    // answers a request that cannot be made in a transaction
+    macro_rules! not_in_transaction {
+        ($resp:path) => {
+            send_resp!($resp("This request cannot be made in a transaction".to_owned()))
+        };
+    }
+
+    // the transaction begun by the client, if any
+    let mut txn: Option<ServerTransaction<E>> = None;
+
+    loop {
+        // the next request must come before the transaction begun expires
+        let timeout = txn.as_ref().map(|open| {
+            let remaining = open.deadline.saturating_duration_since(Instant::now());
+            remaining.max(Duration::from_millis(1))
+        });
+        tcp.set_read_timeout(timeout)?;
+        match reader.fill_buf() {
+            // the client closed the connection
+            Ok([]) => break,
+            Ok(_) => {}
+            Err(e) if txn.is_some() && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                warn!("Transaction of {} timed out, closing the connection", peer_addr);
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let req: Request = read_message(&mut reader)?;
         debug!("Receive request from {}: {:?}", peer_addr, req);
+        if let Some(open) = txn.as_mut() {
+            match req {
+                Request::Get { key } => send_resp!(match open.get(&key) {
+                    Ok(value) => GetResponse::Ok(value),
+                    Err(e) => GetResponse::Err(format!("{}", e)),
+                }),
+                Request::Set { key, value } => {
+                    open.writes.insert(key, Some(value));
+                    send_resp!(SetResponse::Ok(()))
+                }
+                Request::Remove { key } => send_resp!(match open.remove(key) {
+                    Ok(_) => RemoveResponse::Ok(()),
+                    Err(e) => RemoveResponse::Err(format!("{}", e)),
+                }),
+                Request::Commit => {
+                    let open = txn.take().unwrap();
+                    send_resp!(match open.commit(&engine) {
+                        Ok(_) => TransactionResponse::Ok(()),
+                        Err(KvsError::Conflict) => TransactionResponse::Conflict,
+                        Err(e) => TransactionResponse::Err(format!("{}", e)),
+                    })
+                }
+                Request::Rollback => {
+                    txn = None;
+                    send_resp!(TransactionResponse::Ok(()))
+                }
+                Request::Begin => send_resp!(TransactionResponse::Err(
+                    "A transaction is already open".to_owned()
+                )),
+                Request::SetWithTtl { .. } => not_in_transaction!(SetResponse::Err),
+                Request::CompareAndSwap { .. } | Request::SetIfAbsent { .. } => {
+                    not_in_transaction!(CasResponse::Err)
+                }
+                Request::Batch { .. } => not_in_transaction!(BatchResponse::Err),
+                Request::Scan { .. } | Request::ScanPrefix { .. } => {
+                    not_in_transaction!(ScanResponse::Err)
+                }
+                Request::Checkpoint { .. } => not_in_transaction!(CheckpointResponse::Err),
+            };
+            continue;
+        }
         match req {
-            Request::Get { key } => send_resp!(match engine.get(key) {
+            Request::Get { key } => send_resp!(match engine.get(&key) {
                 Ok(value) => GetResponse::Ok(value),
                 Err(e) => GetResponse::Err(format!("{}", e)),
             }),
@@ -65,11 +338,187 @@ fn serve<E: KvsEngine>(engine: E, tcp : TcpStream) -> Result<()> {
                 Ok(_) => SetResponse::Ok(()),
                 Err(e) => SetResponse::Err(format!("{}", e)),
             }),
-            Request::Remove { key } => send_resp!(match engine.remove(key) {
+            Request::SetWithTtl { key, value, ttl } => {
+                send_resp!(match engine.set_with_ttl(key, value, ttl) {
+                    Ok(_) => SetResponse::Ok(()),
+                    Err(e) => SetResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::Remove { key } => send_resp!(match engine.remove(&key) {
                 Ok(_) => RemoveResponse::Ok(()),
                 Err(e) => RemoveResponse::Err(format!("{}", e)),
             }),
+            Request::CompareAndSwap { key, expected, new } => {
+                send_resp!(cas_response(engine.compare_and_swap(key, expected, new)))
+            }
+            Request::SetIfAbsent { key, value } => {
+                send_resp!(cas_response(engine.set_if_absent(key, value)))
+            }
+            Request::Batch { batch } => send_resp!(match engine.write_batch(batch) {
+                Ok(_) => BatchResponse::Ok(()),
+                Err(e) => BatchResponse::Err(format!("{}", e)),
+            }),
+            Request::Scan { start, end, options } => {
+                let start = start.map_or(Bound::Unbounded, Bound::Included);
+                let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+                send_scan(&mut writer, engine.scan((start, end), options))?;
+                debug!("Scan sent to {}", peer_addr);
+            }
+            Request::ScanPrefix { prefix, options } => {
+                send_scan(&mut writer, engine.scan_prefix(prefix, options))?;
+                debug!("Scan sent to {}", peer_addr);
+            }
+            Request::Begin => send_resp!(match engine.snapshot() {
+                Ok(snapshot) => {
+                    txn = Some(ServerTransaction::new(snapshot, transaction_timeout));
+                    TransactionResponse::Ok(())
+                }
+                Err(e) => TransactionResponse::Err(format!("{}", e)),
+            }),
+            Request::Checkpoint { dest } => {
+                let res = checkpoint_path(checkpoint_dir, &dest)
+                    .and_then(|dest| engine.checkpoint(dest));
+                send_resp!(match res {
+                    Ok(_) => CheckpointResponse::Ok(()),
+                    Err(e) => CheckpointResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::Commit | Request::Rollback => {
+                send_resp!(TransactionResponse::Err("No transaction is open".to_owned()))
+            }
         };
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+
+/// A transaction spanning several requests of a connection.
+///
+/// Unlike with `KvsEngine::transaction`, the client makes the reads and writes
+/// itself, so they cannot be replayed. They are checked instead on commit: the
+/// transaction conflicts if a key it used no longer has the value it saw.
+struct ServerTransaction<E: KvsEngine> {
+    snapshot: E::Snapshot,
+    // time the connection is closed if the transaction is still open
+    deadline: Instant,
+    // the value of each key read, as the snapshot saw it
+    reads: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    // the latest write of each key, `None` for a removal
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+
+impl<E: KvsEngine> ServerTransaction<E> {
+    fn new(snapshot: E::Snapshot, timeout: Duration) -> Self {
+        ServerTransaction {
+            snapshot,
+            deadline: Instant::now() + timeout,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        let value = self.snapshot.get(key)?;
+        self.reads.insert(key.to_vec(), value.clone());
+        Ok(value)
+    }
+
+    /// Fails with `KvsError::KeyNotFound`, as documented for `KvsEngine::remove`.
+    fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.get(&key)?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    fn commit(self, engine: &E) -> Result<()> {
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        engine.transaction(|txn| {
+            for (key, seen) in &self.reads {
+                if txn.get(key)? != *seen {
+                    return Err(KvsError::Conflict);
+                }
+            }
+            // a key written blindly conflicts too if it changed since `Begin`
+            for key in self.writes.keys() {
+                if !self.reads.contains_key(key) && txn.get(key)? != self.snapshot.get(key)? {
+                    return Err(KvsError::Conflict);
+                }
+            }
+            for (key, value) in &self.writes {
+                match value {
+                    Some(value) => txn.set(key.clone(), value.clone())?,
+                    None => txn.remove(key.clone())?,
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+
+/// Resolves the destination of a checkpoint requested by a client under
+/// `checkpoint_dir`, so that clients cannot write anywhere else.
+fn checkpoint_path(checkpoint_dir: Option<&Path>, dest: &Path) -> Result<PathBuf> {
+    let checkpoint_dir = checkpoint_dir.ok_or_else(|| {
+        KvsError::StringError("Checkpoints are disabled on this server".to_owned())
+    })?;
+    let mut components = dest.components().peekable();
+    if components.peek().is_none() || !components.all(|c| matches!(c, Component::Normal(_))) {
+        return Err(KvsError::StringError(format!(
+            "Invalid checkpoint path {}, it must be relative and must not contain `..`",
+            dest.display()
+        )));
+    }
+    Ok(checkpoint_dir.join(dest))
+}
+
+
+fn cas_response(swapped: Result<bool>) -> CasResponse {
+    match swapped {
+        Ok(swapped) => CasResponse::Ok(swapped),
+        Err(e) => CasResponse::Err(format!("{}", e)),
+    }
+}
+
+
+/// Sends the pairs found by a scan as they are read, so that neither side holds
+/// them all in a single message.
+fn send_scan<W: Write>(writer: &mut W, iter: Result<ScanIter>) -> Result<()> {
+    let mut send = |resp: &ScanResponse| -> Result<()> {
+        bincode::serialize_into(&mut *writer, resp)?;
+        writer.flush()?;
+        Ok(())
+    };
+    let iter = match iter {
+        Ok(iter) => iter,
+        Err(e) => return send(&ScanResponse::Err(format!("{}", e))),
+    };
+    let mut chunk = Vec::new();
+    let mut chunk_size = 0;
+    for pair in iter {
+        match pair {
+            Ok((key, value)) => {
+                chunk_size += key.len() + value.len();
+                chunk.push((key, value));
+            }
+            Err(e) => return send(&ScanResponse::Err(format!("{}", e))),
+        }
+        if chunk_size >= SCAN_CHUNK_SIZE {
+            send(&ScanResponse::Pairs(chunk))?;
+            chunk = Vec::new();
+            chunk_size = 0;
+        }
+    }
+    if !chunk.is_empty() {
+        send(&ScanResponse::Pairs(chunk))?;
+    }
+    send(&ScanResponse::Done)
+}
@@ -2,7 +2,11 @@
 use crate::Result;
 
 mod naive;
+mod rayon;
+mod shared_queue;
 pub use self::naive::NaiveThreadPool;
+pub use self::rayon::RayonThreadPool;
+pub use self::shared_queue::SharedQueueThreadPool;
 
 
 pub trait ThreadPool {
@@ -0,0 +1,30 @@
+use log::error;
+
+use super::ThreadPool;
+use crate::{KvsError, Result};
+
+
+/// Wrapper of `rayon::ThreadPool`
+///
+/// A job that panics is logged and the worker thread goes on with the next job,
+/// rather than rayon aborting the process.
+pub struct RayonThreadPool(rayon::ThreadPool);
+
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            .panic_handler(|_| error!("A job of the thread pool panicked."))
+            .build()
+            .map_err(|e| KvsError::StringError(format!("{}", e)))?;
+        Ok(RayonThreadPool(pool))
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.0.spawn(job)
+    }
+}
@@ -0,0 +1,73 @@
+use std::thread;
+
+use crossbeam::channel::{self, Receiver, Sender};
+use log::{debug, error};
+
+use super::ThreadPool;
+use crate::Result;
+
+
+/// A thread pool with a fixed number of workers pulling jobs from a shared queue.
+///
+/// If a spawned job panics, the worker thread running it dies and a new worker
+/// is spawned in its place, so the pool never shrinks.
+pub struct SharedQueueThreadPool {
+    tx: Sender<Box<dyn FnOnce() + Send + 'static>>,
+}
+
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let (tx, rx) = channel::unbounded::<Box<dyn FnOnce() + Send + 'static>>();
+        for _ in 0..threads {
+            let rx = JobReceiver(rx.clone());
+            thread::Builder::new().spawn(move || run_jobs(rx))?;
+        }
+        Ok(SharedQueueThreadPool { tx })
+    }
+
+    /// Spawns a job into the thread pool.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the thread pool has no thread.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.tx
+            .send(Box::new(job))
+            .expect("The thread pool has no thread.");
+    }
+}
+
+
+// The receiver is dropped while unwinding when a job panics,
+// which is the moment we respawn a worker to replace the dead one.
+#[derive(Clone)]
+struct JobReceiver(Receiver<Box<dyn FnOnce() + Send + 'static>>);
+
+
+impl Drop for JobReceiver {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let rx = self.clone();
+            if let Err(e) = thread::Builder::new().spawn(move || run_jobs(rx)) {
+                error!("Failed to spawn a thread: {}", e);
+            }
+        }
+    }
+}
+
+
+fn run_jobs(rx: JobReceiver) {
+    loop {
+        match rx.0.recv() {
+            Ok(job) => job(),
+            Err(_) => {
+                debug!("Thread exits because the thread pool is destroyed.");
+                break;
+            }
+        }
+    }
+}
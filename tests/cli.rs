@@ -1,10 +1,13 @@
 use assert_cmd::prelude::*;
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::io::Write;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
+use kvs::{KvStore, KvsEngine, SledKvsEngine};
 use tempfile::TempDir;
 
 // `kvs-client` with no args should exit with a non-zero code.
@@ -20,28 +23,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +55,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +94,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +126,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +137,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -146,7 +149,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -159,13 +162,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -182,15 +186,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -201,15 +206,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -223,19 +229,20 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -243,7 +250,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -251,7 +258,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -259,7 +266,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -267,7 +274,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -275,7 +282,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -283,7 +290,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -291,7 +298,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -304,26 +311,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -334,6 +342,31 @@ fn cli_access_server(engine: &str, addr: &str) {
 
 
 
+// A second `kvs-server` on the same directory should exit with a non-zero code.
+#[test]
+fn cli_directory_in_use() {
+    for engine in &["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = cmd
+            .args(["--engine", engine, "--addr", "127.0.0.1:4017"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        cmd.args(["--engine", engine, "--addr", "127.0.0.1:4018"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("used by another store"));
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    }
+}
+
+
 #[test]
 fn cli_access_server_kvs_engine() {
     cli_access_server("kvs", "127.0.0.1:4004");
@@ -343,4 +376,471 @@ fn cli_access_server_kvs_engine() {
 #[test]
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
-}
\ No newline at end of file
+}
+
+#[test]
+fn cli_invalid_pool() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--pool", "unknown", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--threads", "0", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+
+#[test]
+fn cli_access_server_with_pools() {
+    for (pool, addr) in &[
+        ("naive", "127.0.0.1:4007"),
+        ("shared-queue", "127.0.0.1:4008"),
+        ("rayon", "127.0.0.1:4009"),
+    ] {
+        let (sender, receiver) = mpsc::sync_channel(0);
+        let temp_dir = TempDir::new().unwrap();
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(["--pool", pool, "--threads", "2", "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        let handle = thread::spawn(move || {
+            let _ = receiver.recv(); // wait for main thread to finish
+            child.kill().expect("server exited before killed");
+            child.wait().unwrap();
+        });
+        thread::sleep(Duration::from_secs(1));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", "value1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value1\n");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+
+// `kvs-server` should exit cleanly on SIGTERM and keep the data written
+#[cfg(unix)]
+#[test]
+fn cli_graceful_shutdown() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4010";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    assert!(child.wait().unwrap().success());
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+
+// `kvs-client scan` should list ranges and prefixes in key order
+#[test]
+fn cli_scan() {
+    for (engine, addr) in &[("kvs", "127.0.0.1:4011"), ("sled", "127.0.0.1:4012")] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(["--engine", engine, "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        for key in &["a1", "a2", "a3", "b1"] {
+            Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(["set", key, &format!("v{}", key), "--addr", addr])
+                .current_dir(&temp_dir)
+                .assert()
+                .success();
+        }
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["scan", "a2", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("a2\tva2\na3\tva3\nb1\tvb1\n");
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["scan", "a1", "a3", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("a1\tva1\na2\tva2\n");
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["scan", "--prefix", "a", "--reverse", "--limit", "2", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("a3\tva3\na2\tva2\n");
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["scan", "a1", "--prefix", "a", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    }
+}
+
+
+// `kvs-client cas` should fail when the current value does not match
+#[test]
+fn cli_cas() {
+    for (engine, addr) in &[("kvs", "127.0.0.1:4013"), ("sled", "127.0.0.1:4014")] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(["--engine", engine, "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["cas", "lease", "--new", "owner1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["cas", "lease", "--new", "owner2", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("does not match"));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["cas", "lease", "--expected", "owner1", "--new", "owner2", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "lease", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("owner2\n");
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["cas", "lease", "--expected", "owner2", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "lease", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(contains("Key not found"));
+
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    }
+}
+
+
+// `kvs-client set --ttl` should make the key expire
+#[test]
+fn cli_set_ttl() {
+    for (engine, addr) in &[("kvs", "127.0.0.1:4015"), ("sled", "127.0.0.1:4016")] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(["--engine", engine, "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "session", "alice", "--ttl", "1s", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "session", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("alice\n");
+
+        thread::sleep(Duration::from_millis(1500));
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "session", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(contains("Key not found"));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "session", "alice", "--ttl", "soon", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "session", "alice", "--ttl", "18446744073709551615h", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("invalid duration"));
+
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    }
+}
+
+
+// `kvs-client set --ttl` should reject a zero TTL, the value would have expired already
+#[test]
+fn cli_set_zero_ttl() {
+    let temp_dir = TempDir::new().unwrap();
+    for ttl in &["0", "0s", "0ms", "0h"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "session", "alice", "--ttl", ttl])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("invalid duration"));
+    }
+}
+#[test]
+fn cli_checkpoint() {
+    for (engine, addr) in &[("kvs", "127.0.0.1:4029"), ("sled", "127.0.0.1:4030")] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(["--engine", engine, "--addr", addr, "--checkpoint-dir", "checkpoints"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        let client = |args: &[&str]| {
+            let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+            cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+            cmd
+        };
+        client(&["set", "key1", "value1"]).assert().success();
+        client(&["checkpoint", "backup"])
+            .assert()
+            .success()
+            .stdout(is_empty());
+        client(&["set", "key2", "value2"]).assert().success();
+        client(&["checkpoint", "backup"]).assert().failure();
+        client(&["checkpoint", "../escape"]).assert().failure();
+        let escape = temp_dir.path().join("escape");
+        client(&["checkpoint", escape.to_str().unwrap()]).assert().failure();
+        assert!(!escape.exists());
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+
+        // the checkpoint records its engine
+        let backup_dir = temp_dir.path().join("checkpoints").join("backup");
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(["--addr", addr])
+            .current_dir(&backup_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        client(&["get", "key1"]).assert().success().stdout("value1\n");
+        client(&["get", "key2"])
+            .assert()
+            .success()
+            .stdout(contains("Key not found"));
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    }
+}
+
+
+// `kvs-admin migrate` should copy a directory into another engine, and
+// `kvs-admin dump` and `restore` move the data through a dump
+#[test]
+fn cli_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let kvs_dir = temp_dir.path().join("kvs-data");
+    {
+        let store = KvStore::open(&kvs_dir).unwrap();
+        store.set("key1", "value1").unwrap();
+        store.set("key2", "value2").unwrap();
+        store.set_with_ttl("key3", "value3", Duration::from_secs(60)).unwrap();
+    }
+    fs::write(kvs_dir.join("engine"), "kvs").unwrap();
+    let admin = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-admin").unwrap();
+        cmd.args(args).current_dir(&temp_dir);
+        cmd
+    };
+
+    admin(&["migrate", "--from", "sled", "--to", "kvs", "kvs-data", "other"])
+        .assert()
+        .failure()
+        .stderr(contains("kvs engine"));
+    // a migration that fails leaves no destination behind
+    {
+        let _store = KvStore::open(&kvs_dir).unwrap();
+        admin(&["migrate", "--from", "kvs", "--to", "sled", "kvs-data", "failed"])
+            .assert()
+            .failure();
+    }
+    assert!(!temp_dir.path().join("failed").exists());
+    admin(&["migrate", "--from", "kvs", "--to", "sled", "kvs-data", "sled-data"])
+        .assert()
+        .success()
+        .stdout("Migrated 3 keys from kvs to sled\n");
+    admin(&["migrate", "--from", "kvs", "--to", "sled", "kvs-data", "sled-data"])
+        .assert()
+        .failure();
+    let sled_dir = temp_dir.path().join("sled-data");
+    assert_eq!(fs::read_to_string(sled_dir.join("engine")).unwrap(), "sled");
+
+    let output = admin(&["dump", "sled-data"]).output().unwrap();
+    assert!(output.status.success());
+    admin(&["restore", "--engine", "kvs", "restored"])
+        .with_stdin()
+        .buffer(output.stdout)
+        .assert()
+        .success()
+        .stdout("Restored 3 keys\n");
+    let store = KvStore::open(temp_dir.path().join("restored")).unwrap();
+    assert_eq!(store.get_string("key1").unwrap(), Some("value1".to_owned()));
+    assert_eq!(store.get_string("key3").unwrap(), Some("value3".to_owned()));
+
+    let engine = SledKvsEngine::new(sled::open(&sled_dir).unwrap()).unwrap();
+    assert_eq!(engine.get_string("key2").unwrap(), Some("value2".to_owned()));
+}
+
+
+// `kvs-admin verify` should report the log files and fail if one is damaged
+#[test]
+fn cli_verify() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1", "value1").unwrap();
+        store.set("key1", "value2").unwrap();
+        store.set("key2", "value2").unwrap();
+    }
+    let verify = || {
+        let mut cmd = Command::cargo_bin("kvs-admin").unwrap();
+        cmd.args(["verify", "."]).current_dir(&temp_dir);
+        cmd
+    };
+    verify()
+        .assert()
+        .success()
+        .stdout(contains("1.log: ").and(contains("2 keys")))
+        .stderr(is_empty());
+
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("1.log"))
+        .unwrap();
+    file.write_all(b"damage").unwrap();
+    drop(file);
+    verify()
+        .assert()
+        .failure()
+        .stdout(contains("damaged at offset"))
+        .stderr(contains("Damaged log files found"));
+
+    fs::write(temp_dir.path().join("engine"), "sled").unwrap();
+    verify().assert().failure().stderr(contains("sled engine"));
+
+    // log files of older versions are only waiting for the next open
+    fs::remove_file(temp_dir.path().join("engine")).unwrap();
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}"#,
+    )
+    .unwrap();
+    verify()
+        .assert()
+        .success()
+        .stdout(contains("1.log: ").and(contains("needs upgrade")))
+        .stderr(is_empty());
+}
@@ -1,6 +1,13 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    KvStore, KvStoreOptions, KvsEngine, KvsError, KvsSnapshot, RecoveryMode, Result, ScanIter,
+    ScanOptions, SledKvsEngine, SyncPolicy, WriteBatch,
+};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -13,14 +20,14 @@ fn get_stored_value() -> Result<()> {
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get_string("key2")?, Some("value2".to_owned()));
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get_string("key2")?, Some("value2".to_owned()));
 
     Ok(())
 }
@@ -32,16 +39,16 @@ fn overwrite_value() -> Result<()> {
     let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
     store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get_string("key1")?, Some("value2".to_owned()));
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get_string("key1")?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get_string("key1")?, Some("value3".to_owned()));
 
     Ok(())
 }
@@ -53,12 +60,12 @@ fn get_non_existent_value() -> Result<()> {
     let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get_string("key2")?, None);
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get_string("key2")?, None);
 
     Ok(())
 }
@@ -67,7 +74,7 @@ fn get_non_existent_value() -> Result<()> {
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    assert!(store.remove("key1").is_err());
     Ok(())
 }
 
@@ -76,8 +83,8 @@ fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1").is_ok());
+    assert_eq!(store.get_string("key1")?, None);
     Ok(())
 }
 
@@ -119,7 +126,7 @@ fn compaction() -> Result<()> {
         let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+            assert_eq!(store.get_string(key)?, Some(format!("{}", iter)));
         }
         return Ok(());
     }
@@ -133,32 +140,76 @@ fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
     let barrier = Arc::new(Barrier::new(1001));
+    let mut handles = Vec::new();
     for i in 0..1000 {
         let store = store.clone();
         let barrier = barrier.clone();
-        thread::spawn(move || {
+        handles.push(thread::spawn(move || {
             store
                 .set(format!("key{}", i), format!("value{}", i))
                 .unwrap();
             barrier.wait();
-        });
+        }));
     }
     barrier.wait();
 
     for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        assert_eq!(store.get_string(format!("key{}", i))?, Some(format!("value{}", i)));
     }
 
-    // Open from disk again and check persistent data
+    // Open from disk again and check persistent data, once every clone of the
+    // store is dropped so that the directory is unlocked
+    for handle in handles {
+        handle.join().unwrap();
+    }
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
     for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        assert_eq!(store.get_string(format!("key{}", i))?, Some(format!("value{}", i)));
     }
 
     Ok(())
 }
 
+// Writes should keep going, and stay correct, while compactions run in the background
+#[test]
+fn concurrent_set_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(100);
+
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        let value = value.clone();
+        handles.push(thread::spawn(move || {
+            for iter in 0..100 {
+                for key_id in 0..100 {
+                    let key = format!("key{}_{}", thread_id, key_id);
+                    store.set(key, format!("{}{}", value, iter)).unwrap();
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for thread_id in 0..4 {
+            for key_id in 0..100 {
+                let key = format!("key{}_{}", thread_id, key_id);
+                assert_eq!(store.get_string(key)?, Some(format!("{}{}", value, 99)));
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)
+}
+
 #[test]
 fn concurrent_get() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -176,7 +227,7 @@ fn concurrent_get() -> Result<()> {
             for i in 0..100 {
                 let key_id = (i + thread_id) % 100;
                 assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
+                    store.get_string(format!("key{}", key_id)).unwrap(),
                     Some(format!("value{}", key_id))
                 );
             }
@@ -197,7 +248,7 @@ fn concurrent_get() -> Result<()> {
             for i in 0..100 {
                 let key_id = (i + thread_id) % 100;
                 assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
+                    store.get_string(format!("key{}", key_id)).unwrap(),
                     Some(format!("value{}", key_id))
                 );
             }
@@ -210,3 +261,1792 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+
+// A torn write at the tail of the log should be truncated on open
+#[test]
+fn recover_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // cut the last record in half
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    OpenOptions::new().write(true).open(&log)?.set_len(len - 5)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get_string("key2")?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get_string("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A record with a bad checksum at the end of the newest log file should be dropped
+#[test]
+fn recover_checksum_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // flip the last byte of the last value
+    let log = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log)?;
+    *content.last_mut().unwrap() ^= 0xff;
+    fs::write(&log, content)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get_string("key2")?, None);
+    Ok(())
+}
+
+// Log files in an unknown format should not be touched
+#[test]
+fn reject_unknown_log_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("1.log");
+    fs::write(&log, "key1=value1\n")?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnsupportedLogFormat(_)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("opened a log in an unknown format"),
+    }
+    assert!(fs::read_to_string(&log)?.contains("value1"));
+    Ok(())
+}
+
+// The JSON log files of older versions should be converted on open
+#[test]
+fn upgrade_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}"#,
+    )?;
+    // ends with a command torn by a crash
+    fs::write(
+        temp_dir.path().join("2.log"),
+        r#"{"Remove":{"key":"key1"}}{"Set":{"key":"key2","value":"value3"}}{"Set":{"key":"ke"#,
+    )?;
+
+    // a read-only store cannot convert them
+    match KvStore::open_read_only(temp_dir.path()) {
+        Err(KvsError::UnsupportedLogFormat(_)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("opened a JSON log read-only"),
+    }
+    // nor does verify, which reads them as they are
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(report.generations.iter().all(|gen| gen.needs_upgrade));
+    assert_eq!(report.keys, 1);
+    assert_eq!(report.generations[0].corruption, None);
+    assert_eq!(report.generations[0].garbage_bytes, 78);
+    let torn = report.generations[1].corruption.as_ref().unwrap();
+    assert_eq!(torn.offset, 64);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key1")?, None);
+    assert_eq!(store.get_string("key2")?, Some("value3".to_owned()));
+    store.set("key3", "value4")?;
+    drop(store);
+
+    for gen in 1..=2 {
+        let content = fs::read(temp_dir.path().join(format!("{}.log", gen)))?;
+        assert_eq!(&content[..4], b"KVSL");
+    }
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get_string("key1")?, None);
+    assert_eq!(store.get_string("key2")?, Some("value3".to_owned()));
+    assert_eq!(store.get_string("key3")?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// Quarantine mode should move the damaged bytes aside and report them
+#[test]
+fn recovery_quarantine_report() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let valid_len = fs::metadata(&log)?.len();
+    let mut content = fs::read(&log)?;
+    content.extend_from_slice(b"garbage");
+    fs::write(&log, content)?;
+
+    let (store, report) = KvStore::open_with_recovery(temp_dir.path(), RecoveryMode::Quarantine)?;
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+    assert_eq!(report.repairs.len(), 1);
+    let repair = &report.repairs[0];
+    assert_eq!(repair.gen, 1);
+    assert_eq!(repair.offset, valid_len);
+    assert_eq!(repair.bytes_dropped, 7);
+    let corrupt = repair.quarantined_to.as_ref().expect("no quarantine file");
+    assert_eq!(fs::read(corrupt)?, b"garbage");
+    assert_eq!(fs::metadata(&log)?.len(), valid_len);
+
+    // the store is clean now
+    drop(store);
+    let (_, report) = KvStore::open_with_recovery(temp_dir.path(), RecoveryMode::Quarantine)?;
+    assert!(report.is_clean());
+    Ok(())
+}
+
+// Damage in an older log file is not a torn write, so it should be quarantined
+// rather than truncated away
+#[test]
+fn recover_damage_in_sealed_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // flip the last byte of the first value
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    let mut content = fs::read(&log)?;
+    // a header of 8 bytes, then two records of the same length
+    let first_record_end = (8 + (len - 8) / 2) as usize;
+    content[first_record_end - 1] ^= 0xff;
+    fs::write(&log, &content)?;
+
+    let (store, report) = KvStore::open_with_recovery(temp_dir.path(), RecoveryMode::Truncate)?;
+    assert_eq!(store.get_string("key1")?, None);
+    assert_eq!(store.get_string("key3")?, Some("value3".to_owned()));
+    assert_eq!(report.repairs.len(), 1);
+    let repair = &report.repairs[0];
+    assert_eq!(repair.gen, 1);
+    let corrupt = repair.quarantined_to.as_ref().expect("no quarantine file");
+    assert_eq!(fs::read(corrupt)?, &content[repair.offset as usize..]);
+    Ok(())
+}
+
+// Strict mode should refuse to open a damaged store and leave it untouched
+#[test]
+fn recovery_strict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    OpenOptions::new().write(true).open(&log)?.set_len(len - 1)?;
+
+    match KvStore::open_with_recovery(temp_dir.path(), RecoveryMode::Strict) {
+        Err(KvsError::Corruption(_)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("opened a damaged store in strict mode"),
+    }
+    assert_eq!(fs::metadata(&log)?.len(), len - 1);
+    Ok(())
+}
+
+fn log_files(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let mut files: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .collect();
+    files.sort();
+    files
+}
+
+// The active log file should be rotated once it exceeds the max file size
+#[test]
+fn options_max_file_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_file_size(4096)
+        .sync_policy(SyncPolicy::Always);
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let files = log_files(temp_dir.path());
+    assert!(files.len() > 1);
+    for file in &files {
+        // a file is rotated after the write crossing the limit
+        assert!(fs::metadata(file)?.len() < 4096 + 64);
+    }
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    for i in 0..1000 {
+        assert_eq!(store.get_string(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// A read-only store serves reads without writing anything to its directory
+#[test]
+fn options_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let files = log_files(temp_dir.path());
+
+    let options = KvStoreOptions::new().read_only(true);
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+    match store.set("key2".to_owned(), "value2".to_owned()) {
+        Err(KvsError::ReadOnly) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    match store.remove("key1") {
+        Err(KvsError::ReadOnly) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert_eq!(log_files(temp_dir.path()), files);
+
+    let missing = temp_dir.path().join("missing");
+    assert!(KvStore::open_with_options(&missing, &options).is_err());
+    assert!(!missing.exists());
+    Ok(())
+}
+
+// Compaction should not start before the garbage ratio is reached
+#[test]
+fn options_compaction_ratio() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(1024)
+        .compaction_ratio(0.9);
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    // half of the data is garbage, below the ratio
+    for i in 0..200 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    for i in 0..200 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    assert_eq!(log_files(temp_dir.path()).len(), 1);
+
+    assert!(KvStore::open_with_options(
+        temp_dir.path(),
+        &KvStoreOptions::new().compaction_ratio(1.5)
+    )
+    .is_err());
+    Ok(())
+}
+
+// Concurrent writes should be persisted under every sync policy
+#[test]
+fn options_sync_policies() -> Result<()> {
+    let policies = [
+        SyncPolicy::Always,
+        SyncPolicy::EveryN(10),
+        SyncPolicy::Interval(Duration::from_millis(10)),
+    ];
+    for &policy in &policies {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new()
+            .compaction_threshold(4096)
+            .sync_policy(policy);
+        let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+        let mut handles = Vec::new();
+        for thread_id in 0..4 {
+            let store = store.clone();
+            handles.push(thread::spawn(move || {
+                for i in 0..100 {
+                    let key = format!("key{}_{}", thread_id, i % 20);
+                    store.set(key, format!("value{}", i)).unwrap();
+                }
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        store.flush()?;
+
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+        for thread_id in 0..4 {
+            for i in 80..100 {
+                let key = format!("key{}_{}", thread_id, i % 20);
+                assert_eq!(store.get_string(key)?, Some(format!("value{}", i)));
+            }
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for &policy in &[SyncPolicy::EveryN(0), SyncPolicy::Interval(Duration::from_secs(0))] {
+        let options = KvStoreOptions::new().sync_policy(policy);
+        assert!(KvStore::open_with_options(temp_dir.path(), &options).is_err());
+    }
+    Ok(())
+}
+
+// Writes synced together under `SyncPolicy::Always` should be applied in order,
+// and be seen by the writes reading the current values
+#[test]
+fn options_sync_always_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sync_policy(SyncPolicy::Always);
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    store.set("counter", "0")?;
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for i in 0..50 {
+                store.set(format!("key{}", thread_id), format!("value{}", i)).unwrap();
+                // acknowledged writes are applied
+                assert_eq!(
+                    store.get_string(format!("key{}", thread_id)).unwrap(),
+                    Some(format!("value{}", i))
+                );
+                loop {
+                    let current = store.get("counter").unwrap();
+                    let count = String::from_utf8(current.clone().unwrap()).unwrap();
+                    let new = (count.parse::<u64>().unwrap() + 1).to_string().into_bytes();
+                    if store.compare_and_swap("counter", current, Some(new)).unwrap() {
+                        break;
+                    }
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get_string("counter")?, Some("200".to_owned()));
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    assert_eq!(store.get_string("counter")?, Some("200".to_owned()));
+    for thread_id in 0..4 {
+        assert_eq!(store.get_string(format!("key{}", thread_id))?, Some("value49".to_owned()));
+    }
+    Ok(())
+}
+
+// Scans should return the live pairs of a range or prefix in key order
+#[test]
+fn scan_range_and_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["a", "ab", "abc", "b", "c"] {
+        store.set(key.to_string(), format!("value_{}", key))?;
+    }
+    store.set("ab".to_owned(), "new".to_owned())?;
+    store.remove("b")?;
+
+    let keys = |iter: ScanIter| -> Result<Vec<String>> {
+        iter.map(|pair| pair.map(|(key, _)| String::from_utf8(key).unwrap()))
+            .collect()
+    };
+    assert_eq!(
+        store.scan::<&[u8], _>(.., ScanOptions::new())?.collect::<Result<Vec<_>>>()?,
+        vec![
+            (b"a".to_vec(), b"value_a".to_vec()),
+            (b"ab".to_vec(), b"new".to_vec()),
+            (b"abc".to_vec(), b"value_abc".to_vec()),
+            (b"c".to_vec(), b"value_c".to_vec()),
+        ]
+    );
+    assert_eq!(
+        keys(store.scan("ab"..="c", ScanOptions::new())?)?,
+        vec!["ab", "abc", "c"]
+    );
+    assert_eq!(
+        keys(store.scan(.."b", ScanOptions::new().reverse(true).limit(2))?)?,
+        vec!["abc", "ab"]
+    );
+    assert_eq!(
+        keys(store.scan_prefix("ab", ScanOptions::new())?)?,
+        vec!["ab", "abc"]
+    );
+    assert_eq!(
+        keys(store.scan_prefix("a", ScanOptions::new().reverse(true))?)?,
+        vec!["abc", "ab", "a"]
+    );
+    assert!(keys(store.scan_prefix("d", ScanOptions::new())?)?.is_empty());
+
+    // the pairs are found again after reopening
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        keys(store.scan_prefix("", ScanOptions::new())?)?,
+        vec!["a", "ab", "abc", "c"]
+    );
+    Ok(())
+}
+
+fn check_scan_limit_after_expiry<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set_with_ttl("a", "value", Duration::from_millis(10))?;
+    engine.set_with_ttl("b", "value", Duration::from_millis(10))?;
+    engine.set("c", "value")?;
+    engine.set("d", "value")?;
+    thread::sleep(Duration::from_millis(50));
+
+    let keys = |options: ScanOptions| -> Result<Vec<Vec<u8>>> {
+        engine
+            .scan::<&[u8], _>(.., options)?
+            .map(|pair| pair.map(|(key, _)| key))
+            .collect()
+    };
+    assert_eq!(keys(ScanOptions::new().limit(2))?, vec![b"c".to_vec(), b"d".to_vec()]);
+    assert_eq!(keys(ScanOptions::new().limit(1))?, vec![b"c".to_vec()]);
+    engine.set_with_ttl("e", "value", Duration::from_millis(10))?;
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(
+        keys(ScanOptions::new().limit(1).reverse(true))?,
+        vec![b"d".to_vec()]
+    );
+
+    let snapshot = engine.snapshot()?;
+    let snapshot_keys = |options: ScanOptions| -> Result<Vec<Vec<u8>>> {
+        snapshot
+            .scan::<&[u8], _>(.., options)?
+            .map(|pair| pair.map(|(key, _)| key))
+            .collect()
+    };
+    assert_eq!(
+        snapshot_keys(ScanOptions::new().limit(2))?,
+        vec![b"c".to_vec(), b"d".to_vec()]
+    );
+    assert_eq!(
+        snapshot_keys(ScanOptions::new().limit(1).reverse(true))?,
+        vec![b"d".to_vec()]
+    );
+    Ok(())
+}
+
+// The limit of a scan, of the engine or of a snapshot, should count the pairs
+// returned, expired keys excluded
+#[test]
+fn scan_limit_after_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // expired keys stay in the index until the sweeper runs
+    let options = KvStoreOptions::new().sweep_interval(Duration::from_secs(3600));
+    check_scan_limit_after_expiry(KvStore::open_with_options(temp_dir.path(), &options)?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan_limit_after_expiry(SledKvsEngine::new(sled::open(temp_dir.path())?)?)
+}
+
+// A scan should keep returning every pair while compactions move them
+#[test]
+fn scan_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(1024);
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    for i in 0..100 {
+        store.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for _ in 0..50 {
+                for i in 0..100 {
+                    store
+                        .set(format!("key{:03}", i), format!("value{}", i))
+                        .unwrap();
+                }
+            }
+        })
+    };
+    for _ in 0..20 {
+        let pairs = store
+            .scan_prefix("key", ScanOptions::new())?
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(pairs.len(), 100);
+        for (i, (key, value)) in pairs.into_iter().enumerate() {
+            assert_eq!(key, format!("key{:03}", i).into_bytes());
+            assert_eq!(value, format!("value{}", i).into_bytes());
+        }
+    }
+    writer.join().unwrap();
+    Ok(())
+}
+
+// A batch should apply all its writes, in order
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "new1".to_owned());
+    batch.remove("key2");
+    batch.set("key3".to_owned(), "value3".to_owned());
+    batch.set("key3".to_owned(), "new3".to_owned());
+    // removing a missing key in a batch is not an error
+    batch.remove("missing");
+    assert_eq!(batch.len(), 5);
+    store.write_batch(batch)?;
+    store.write_batch(WriteBatch::new())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get_string("key1")?, Some("new1".to_owned()));
+        assert_eq!(store.get_string("key2")?, None);
+        assert_eq!(store.get_string("key3")?, Some("new3".to_owned()));
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)
+}
+
+// A batch torn by a crash should be dropped altogether on open
+#[test]
+fn recover_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "new1".to_owned());
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    // cut the last command of the batch in half
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    OpenOptions::new().write(true).open(&log)?.set_len(len - 5)?;
+
+    let (store, report) = KvStore::open_with_recovery(temp_dir.path(), RecoveryMode::Truncate)?;
+    assert_eq!(report.repairs.len(), 1);
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get_string("key2")?, None);
+    assert_eq!(store.get_string("key3")?, None);
+    store.set("key4".to_owned(), "value4".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get_string("key2")?, None);
+    assert_eq!(store.get_string("key4")?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// Compare-and-swap should only write when the current value is the expected one
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.set_if_absent("key1".to_owned(), "value1".to_owned())?);
+    assert!(!store.set_if_absent("key1".to_owned(), "other".to_owned())?);
+    assert!(!store.compare_and_swap(
+        "key1".to_owned(),
+        Some(b"other".to_vec()),
+        Some(b"value2".to_vec())
+    )?);
+    assert!(store.compare_and_swap(
+        "key1".to_owned(),
+        Some(b"value1".to_vec()),
+        Some(b"value2".to_vec())
+    )?);
+    assert_eq!(store.get_string("key1")?, Some("value2".to_owned()));
+    assert!(store.compare_and_swap("key1".to_owned(), Some(b"value2".to_vec()), None)?);
+    assert_eq!(store.get_string("key1")?, None);
+    assert!(store.compare_and_swap("key1".to_owned(), None, None)?);
+    assert!(!store.compare_and_swap("key1".to_owned(), Some(b"value2".to_vec()), None)?);
+
+    // concurrent increments should not lose updates
+    store.set("counter".to_owned(), "0".to_owned())?;
+    let mut handles = Vec::new();
+    for _ in 0..4 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            let mut done = 0;
+            while done < 50 {
+                let current = store.get_string("counter").unwrap().unwrap();
+                let next = (current.parse::<u32>().unwrap() + 1).to_string();
+                if store
+                    .compare_and_swap("counter".to_owned(), Some(current.into_bytes()), Some(next.into_bytes()))
+                    .unwrap()
+                {
+                    done += 1;
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get_string("counter")?, Some("200".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("counter")?, Some("200".to_owned()));
+    assert_eq!(store.get_string("key1")?, None);
+    Ok(())
+}
+
+fn log_size(dir: &std::path::Path) -> u64 {
+    log_files(dir)
+        .iter()
+        .map(|file| fs::metadata(file).unwrap().len())
+        .sum()
+}
+
+// A key set with a TTL should disappear once it expires
+#[test]
+fn set_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sweep_interval(Duration::from_secs(3600));
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    store.set_with_ttl("key1".to_owned(), "value1".to_owned(), Duration::from_millis(200))?;
+    store.set_with_ttl("key2".to_owned(), "value2".to_owned(), Duration::from_secs(3600))?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get_string("key1")?, None);
+    assert_eq!(store.get_string("key2")?, Some("value2".to_owned()));
+    match store.remove("key1") {
+        Err(KvsError::KeyNotFound) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    let keys: Vec<Vec<u8>> = store
+        .scan::<&[u8], _>(.., ScanOptions::new())?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec![b"key2".to_vec(), b"key3".to_vec()]);
+    assert!(store.set_if_absent("key1".to_owned(), "new1".to_owned())?);
+
+    // a plain set clears the TTL
+    store.set_with_ttl("key3".to_owned(), "value3".to_owned(), Duration::from_millis(100))?;
+    store.set("key3".to_owned(), "new3".to_owned())?;
+    thread::sleep(Duration::from_millis(200));
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    assert_eq!(store.get_string("key1")?, Some("new1".to_owned()));
+    assert_eq!(store.get_string("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get_string("key3")?, Some("new3".to_owned()));
+    Ok(())
+}
+
+// The sweeper should write the removal of expired keys by itself
+#[test]
+fn ttl_sweeper() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sweep_interval(Duration::from_millis(50));
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    for i in 0..100 {
+        store.set_with_ttl(format!("key{}", i), "value".to_owned(), Duration::from_millis(100))?;
+    }
+    store.set("kept".to_owned(), "value".to_owned())?;
+    let size = log_size(temp_dir.path());
+
+    thread::sleep(Duration::from_millis(500));
+    assert!(log_size(temp_dir.path()) > size);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let keys: Vec<Vec<u8>> = store
+        .scan::<&[u8], _>(.., ScanOptions::new())?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec![b"kept".to_vec()]);
+    Ok(())
+}
+
+// The sled engine should remove expired keys from disk without them being read
+#[test]
+fn sled_ttl_sweeper() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::open(temp_dir.path())?;
+    let engine = SledKvsEngine::with_sweep_interval(db.clone(), Duration::from_millis(50))?;
+    for i in 0..100 {
+        engine.set_with_ttl(format!("key{}", i), "value".to_owned(), Duration::from_millis(100))?;
+    }
+    engine.set("kept".to_owned(), "value".to_owned())?;
+    assert_eq!(db.len(), 101);
+
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(db.len(), 1);
+    assert_eq!(db.open_tree("kvs-expiry")?.len(), 0);
+    Ok(())
+}
+
+// Without a sweeper, expired keys should be hidden but left on disk
+#[test]
+fn sled_without_sweeper() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::open(temp_dir.path())?;
+    let engine = SledKvsEngine::without_sweeper(db.clone())?;
+    engine.set_with_ttl("key1", "value1", Duration::from_millis(100))?;
+    engine.set("kept", "value")?;
+
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(db.len(), 2);
+    let snapshot = engine.snapshot()?;
+    assert_eq!(snapshot.get("key1")?, None);
+    assert_eq!(snapshot.entries()?.count(), 1);
+    Ok(())
+}
+
+// Compaction should drop expired entries instead of copying them
+#[test]
+fn compaction_drops_expired() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(64 * 1024)
+        .sweep_interval(Duration::from_secs(3600));
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    let expiring = "v".repeat(10_000);
+    for i in 0..50 {
+        store.set_with_ttl(format!("key{}", i), expiring.clone(), Duration::from_millis(100))?;
+    }
+    thread::sleep(Duration::from_millis(200));
+
+    // stale data triggers a compaction of the first generation
+    let value = "v".repeat(1000);
+    for _ in 0..100 {
+        store.set("other".to_owned(), value.clone())?;
+    }
+    let first_log = temp_dir.path().join("1.log");
+    for _ in 0..100 {
+        if !first_log.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(!first_log.exists());
+    assert!(log_size(temp_dir.path()) < 200 * 1000);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key0")?, None);
+    assert_eq!(store.get_string("other")?, Some(value));
+    Ok(())
+}
+
+// Logs written in the first version of the format should still be read
+#[test]
+fn read_version_1_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut log = b"KVSL".to_vec();
+    log.extend_from_slice(&1u32.to_le_bytes());
+    // bincode of `Set { key: "key1", value: "value1" }` without the expiry time
+    let mut payload = 0u32.to_le_bytes().to_vec();
+    for s in &["key1", "value1"] {
+        payload.extend_from_slice(&(s.len() as u64).to_le_bytes());
+        payload.extend_from_slice(s.as_bytes());
+    }
+    log.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    log.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    log.extend_from_slice(&payload);
+    fs::write(temp_dir.path().join("1.log"), log)?;
+
+    let options = KvStoreOptions::new().compaction_threshold(1024);
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+    // compactions rewrite the old file in the current format
+    for i in 0..100 {
+        store.set("key2".to_owned(), format!("value{}", i))?;
+    }
+    for _ in 0..100 {
+        if !temp_dir.path().join("1.log").exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get_string("key2")?, Some("value99".to_owned()));
+    Ok(())
+}
+
+
+// Keys and values should be arbitrary bytes, not only UTF-8 strings
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = vec![0, 159, 146, 150, 255];
+    store.set(vec![0xff, 0x00], value.clone())?;
+    store.set(vec![0xff, 0xff, 0x01], vec![1])?;
+    store.set(vec![0xfe], vec![2])?;
+    store.set("text", "value")?;
+
+    assert_eq!(store.get(&[0xff, 0x00][..])?, Some(value.clone()));
+    match store.get_string(&[0xff, 0x00][..]) {
+        Err(KvsError::Utf8(_)) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert_eq!(store.get_string("text")?, Some("value".to_owned()));
+    let keys: Vec<Vec<u8>> = store
+        .scan_prefix([0xff], ScanOptions::new())?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec![vec![0xff, 0x00], vec![0xff, 0xff, 0x01]]);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(&[0xff, 0x00][..])?, Some(value));
+    store.remove([0xfe])?;
+    assert_eq!(store.get([0xfe])?, None);
+    Ok(())
+}
+
+
+fn hint_files(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let mut files: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("hint".as_ref()))
+        .collect();
+    files.sort();
+    files
+}
+
+// Compactions should leave hint files that open uses, or ignores when they are damaged
+#[test]
+fn hint_files_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(1024);
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    store.set_with_ttl("ttl", "value", Duration::from_secs(3600))?;
+    for iter in 0..50 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("{}", iter))?;
+        }
+        // a compaction finished
+        if !temp_dir.path().join("1.log").exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    store.remove("key0")?;
+    let expected: Vec<_> = store
+        .scan::<&[u8], _>(.., ScanOptions::new())?
+        .collect::<Result<_>>()?;
+    drop(store);
+
+    let hints = hint_files(temp_dir.path());
+    assert!(!hints.is_empty());
+    // every hint belongs to a log file
+    for hint in &hints {
+        assert!(hint.with_extension("log").exists());
+    }
+    let check = || -> Result<()> {
+        let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+        let pairs: Vec<_> = store
+            .scan::<&[u8], _>(.., ScanOptions::new())?
+            .collect::<Result<_>>()?;
+        assert_eq!(pairs, expected);
+        assert_eq!(store.get("key0")?, None);
+        assert_eq!(store.get_string("ttl")?, Some("value".to_owned()));
+        Ok(())
+    };
+    check()?;
+
+    // a damaged hint is ignored and the log replayed instead
+    for hint in &hints {
+        let mut bytes = fs::read(hint)?;
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(hint, bytes)?;
+    }
+    check()?;
+    for hint in &hints {
+        fs::remove_file(hint)?;
+    }
+    check()
+}
+
+
+// Compactions should split their output at the max file size too
+#[test]
+fn compaction_max_file_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_file_size(4096)
+        .compaction_threshold(16 * 1024)
+        // every file holding garbage is compacted
+        .file_dead_ratio(0.0);
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), "value")?;
+    }
+    // leave some garbage in every file
+    for i in (0..1000).step_by(50) {
+        store.set(format!("key{}", i), "value")?;
+    }
+    for iter in 0..100_000 {
+        store.set("hot", format!("value{}", iter))?;
+        // a compaction finished
+        if !temp_dir.path().join("1.log").exists() {
+            break;
+        }
+    }
+    drop(store);
+
+    let hints = hint_files(temp_dir.path());
+    assert!(hints.len() > 1);
+    for file in log_files(temp_dir.path()) {
+        assert!(fs::metadata(file)?.len() < 4096 + 64);
+    }
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    let pairs = store
+        .scan_prefix("key", ScanOptions::new())?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs.len(), 1000);
+    let value = &pairs[0].1;
+    assert!(pairs.iter().all(|(_, v)| v == value));
+    Ok(())
+}
+
+
+// Compactions should only rewrite the log files made mostly of garbage, keeping
+// the removals that hide values of the files left untouched
+#[test]
+fn compaction_skips_live_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_file_size(16 * 1024)
+        .compaction_threshold(16 * 1024)
+        .file_dead_ratio(0.5);
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    let value = "v".repeat(1000);
+    store.set("removed", value.clone())?;
+    for i in 0..31 {
+        store.set(format!("cold{}", i), value.clone())?;
+    }
+    let first_log = temp_dir.path().join("1.log");
+    let first_content = fs::read(&first_log)?;
+    store.remove("removed")?;
+    // the generation holding the removal
+    let active_log = log_files(temp_dir.path())
+        .into_iter()
+        .max_by_key(|path| path.file_stem().unwrap().to_str().unwrap().parse::<u64>().unwrap())
+        .unwrap();
+
+    for iter in 0..100 {
+        for _ in 0..100 {
+            store.set("hot", format!("{}{}", iter, value))?;
+        }
+        if !active_log.exists() {
+            break;
+        }
+    }
+    assert!(!active_log.exists());
+    assert_eq!(fs::read(&first_log)?, first_content);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    assert_eq!(store.get_string("removed")?, None);
+    for i in 0..31 {
+        assert_eq!(store.get_string(format!("cold{}", i))?, Some(value.clone()));
+    }
+    assert!(store.get_string("hot")?.is_some());
+    Ok(())
+}
+
+
+// A log file should only be compacted once its garbage, expired values included,
+// makes up more than the dead ratio of it
+#[test]
+fn compaction_dead_ratio() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(64 * 1024)
+        .file_dead_ratio(0.5)
+        .sweep_interval(Duration::from_secs(3600));
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    let cold = "v".repeat(10_000);
+    for i in 0..30 {
+        store.set(format!("cold{}", i), cold.clone())?;
+    }
+    for i in 0..25 {
+        store.set_with_ttl(format!("expiring{}", i), cold.clone(), Duration::from_millis(500))?;
+    }
+    let value = "v".repeat(1000);
+    for _ in 0..100 {
+        store.set("other", value.clone())?;
+    }
+    // over the compaction threshold, but most of the file is live
+    thread::sleep(Duration::from_millis(700));
+    let first_log = temp_dir.path().join("1.log");
+    assert!(first_log.exists());
+
+    // the expired values make up the rest
+    store.set("other", value.clone())?;
+    for _ in 0..100 {
+        if !first_log.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(!first_log.exists());
+    assert!(log_size(temp_dir.path()) < 350 * 1000);
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    assert_eq!(store.get_string("expiring0")?, None);
+    assert_eq!(store.get_string("cold0")?, Some(cold));
+    assert_eq!(store.get_string("other")?, Some(value));
+    Ok(())
+}
+
+
+// Writes made in the same millisecond as a compaction that found nothing to
+// compact should not fail while a value with a TTL is live
+#[test]
+fn compaction_same_millisecond() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(1024)
+        .file_dead_ratio(0.9)
+        .sweep_interval(Duration::from_secs(3600));
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    store.set_with_ttl("ttl", "value", Duration::from_secs(3600))?;
+    let value = "v".repeat(100);
+    for i in 0..100 {
+        store.set(format!("cold{}", i), value.clone())?;
+    }
+    // over the compaction threshold, but far below the dead ratio
+    for _ in 0..20 {
+        store.set("hot", value.clone())?;
+    }
+    // each of them looks for files to compact, many within the same millisecond
+    for i in 0..1000 {
+        store.set(format!("new{}", i), "value")?;
+    }
+    assert_eq!(store.get_string("ttl")?, Some("value".to_owned()));
+    assert_eq!(store.get_string("new999")?, Some("value".to_owned()));
+    Ok(())
+}
+
+
+// A directory should only be opened by one store at a time
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::DirectoryInUse(path)) => assert_eq!(path, temp_dir.path()),
+        res => panic!("the second open should fail, got {:?}", res.map(|_| ())),
+    }
+    // clones share the lock
+    let clone = store.clone();
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(clone);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+    Ok(())
+}
+
+
+fn dir_entries(dir: &std::path::Path) -> Vec<(std::path::PathBuf, u64)> {
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let len = fs::metadata(&path).unwrap().len();
+            (path, len)
+        })
+        .collect();
+    entries.sort();
+    entries
+}
+
+// Read-only stores should share a directory with each other, but not with a
+// store opened for writing
+#[test]
+fn read_only_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    match KvStore::open_read_only(temp_dir.path()) {
+        Err(KvsError::DirectoryInUse(_)) => {}
+        res => panic!("the read-only open should fail, got {:?}", res.map(|_| ())),
+    }
+    drop(store);
+    let entries = dir_entries(temp_dir.path());
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    let other = KvStore::open_read_only(temp_dir.path())?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::DirectoryInUse(_)) => {}
+        res => panic!("the open should fail, got {:?}", res.map(|_| ())),
+    }
+    let pairs = store
+        .scan::<&[u8], _>(.., ScanOptions::new())?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs.len(), 2);
+    assert_eq!(other.get_string("key2")?, Some("value2".to_owned()));
+    let mut batch = WriteBatch::new();
+    batch.set("key3", "value3");
+    match store.write_batch(batch) {
+        Err(KvsError::ReadOnly) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    drop(store);
+    drop(other);
+    assert_eq!(dir_entries(temp_dir.path()), entries);
+
+    // a copy of the logs without the lock file is opened without creating it
+    let copy_dir = TempDir::new().expect("unable to create temporary working directory");
+    for file in log_files(temp_dir.path()) {
+        fs::copy(&file, copy_dir.path().join(file.file_name().unwrap()))?;
+    }
+    let entries = dir_entries(copy_dir.path());
+    let store = KvStore::open_read_only(copy_dir.path())?;
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+    assert_eq!(dir_entries(copy_dir.path()), entries);
+    Ok(())
+}
+
+
+fn check_snapshot<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("key1", "value1")?;
+    engine.set("key2", "value2")?;
+    let snapshot = engine.snapshot()?;
+    engine.set("key1", "value1b")?;
+    engine.remove("key2")?;
+    engine.set("key3", "value3")?;
+
+    assert_eq!(snapshot.get_string("key1")?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get_string("key2")?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get_string("key3")?, None);
+    let pairs = snapshot
+        .scan_prefix("key", ScanOptions::new())?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"key1".to_vec(), b"value1".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec()),
+        ]
+    );
+    assert_eq!(engine.get_string("key1")?, Some("value1b".to_owned()));
+    assert_eq!(engine.get_string("key2")?, None);
+
+    let later = engine.snapshot()?;
+    assert_eq!(later.get_string("key1")?, Some("value1b".to_owned()));
+    assert_eq!(later.get_string("key2")?, None);
+    assert_eq!(snapshot.get_string("key1")?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A snapshot should keep reading the values as they were when it was taken
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_snapshot(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_snapshot(SledKvsEngine::new(sled::open(temp_dir.path())?)?)
+}
+
+
+fn check_snapshot_concurrent_writes<E: KvsEngine>(engine: E) -> Result<()> {
+    let writers: Vec<_> = (0..4)
+        .map(|i| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for n in 0..500 {
+                    engine.set(format!("key{}", i), n.to_string())?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    // snapshots are taken and dropped in any order while the keys are written
+    let mut snapshots = Vec::new();
+    for round in 0..50 {
+        let snapshot = engine.snapshot()?;
+        let values: Vec<_> = (0..4)
+            .map(|i| snapshot.get_string(format!("key{}", i)))
+            .collect::<Result<_>>()?;
+        snapshots.push((snapshot, values));
+        if round % 3 == 0 {
+            snapshots.remove(round % snapshots.len());
+        }
+        for (snapshot, values) in &snapshots {
+            for (i, value) in values.iter().enumerate() {
+                assert_eq!(&snapshot.get_string(format!("key{}", i))?, value);
+            }
+        }
+    }
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    Ok(())
+}
+
+// Snapshots taken while writes go on should each keep the values they saw first
+#[test]
+fn snapshot_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_snapshot_concurrent_writes(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_snapshot_concurrent_writes(SledKvsEngine::new(sled::open(temp_dir.path())?)?)
+}
+
+fn check_snapshot_reverse_scan<E: KvsEngine>(engine: E) -> Result<()> {
+    for i in 0..5 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let snapshot = engine.snapshot()?;
+    engine.remove("key4")?;
+    engine.remove("key2")?;
+    engine.set("key3", "value3b")?;
+    engine.set("key5", "value5")?;
+
+    let pairs = snapshot
+        .scan_prefix("key", ScanOptions::new().reverse(true).limit(3))?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"key4".to_vec(), b"value4".to_vec()),
+            (b"key3".to_vec(), b"value3".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec()),
+        ]
+    );
+    Ok(())
+}
+
+// Reverse scans of a snapshot should see the keys removed since it was taken
+#[test]
+fn snapshot_reverse_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_snapshot_reverse_scan(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_snapshot_reverse_scan(SledKvsEngine::new(sled::open(temp_dir.path())?)?)
+}
+
+// Compactions should keep the log files an open snapshot reads from
+#[test]
+fn snapshot_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(16 * 1024);
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let snapshot = store.snapshot()?;
+    for iter in 0..1000 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("new{}", iter))?;
+        }
+        // a second compaction wrote its output, so the first one finished
+        if hint_files(temp_dir.path()).len() > 1 {
+            break;
+        }
+    }
+    let first_log = temp_dir.path().join("1.log");
+    assert!(first_log.exists());
+    for i in 0..100 {
+        assert_eq!(snapshot.get_string(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(
+        snapshot
+            .scan_prefix("key", ScanOptions::new())?
+            .collect::<Result<Vec<_>>>()?
+            .len(),
+        100
+    );
+    drop(snapshot);
+    assert!(!first_log.exists());
+    Ok(())
+}
+
+
+// A log file removed behind the store's back should not make every later
+// compaction fail
+#[test]
+fn compaction_missing_log_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(16 * 1024)
+        .max_file_size(4 * 1024);
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    fs::remove_file(temp_dir.path().join("1.log"))?;
+
+    for iter in 0..1000 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("new{}", iter))?;
+        }
+        if !hint_files(temp_dir.path()).is_empty() {
+            break;
+        }
+    }
+    assert!(!hint_files(temp_dir.path()).is_empty());
+    Ok(())
+}
+
+// A snapshot should keep the directory locked once its store is dropped, until
+// it removes the files compacted meanwhile
+#[test]
+fn snapshot_outlives_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(16 * 1024);
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let snapshot = store.snapshot()?;
+    for iter in 0..1000 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("new{}", iter))?;
+        }
+        if hint_files(temp_dir.path()).len() > 1 {
+            break;
+        }
+    }
+    drop(store);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::DirectoryInUse(_)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("opened a directory locked by a snapshot"),
+    }
+    assert_eq!(snapshot.get_string("key0")?, Some("value0".to_owned()));
+    drop(snapshot);
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    let value = store.get_string("key0")?.unwrap();
+    assert!(value.starts_with("new"));
+    for i in 0..100 {
+        assert_eq!(store.get_string(format!("key{}", i))?, Some(value.clone()));
+    }
+    Ok(())
+}
+
+fn check_transaction<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("key1", "value1")?;
+    let value = engine.transaction(|txn| {
+        txn.set("key2", "value2")?;
+        txn.remove("key1")?;
+        assert_eq!(txn.get("key1")?, None);
+        txn.get_string("key2")
+    })?;
+    assert_eq!(value, Some("value2".to_owned()));
+    assert_eq!(engine.get("key1")?, None);
+    assert_eq!(engine.get_string("key2")?, Some("value2".to_owned()));
+
+    // a failed transaction writes nothing
+    let res: Result<()> = engine.transaction(|txn| {
+        txn.set("key2", "value2b")?;
+        Err(KvsError::StringError("abort".to_owned()))
+    });
+    assert!(res.is_err());
+    assert_eq!(engine.get_string("key2")?, Some("value2".to_owned()));
+
+    // concurrent increments are all counted
+    let barrier = Arc::new(Barrier::new(4));
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let engine = engine.clone();
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || -> Result<()> {
+                barrier.wait();
+                for _ in 0..25 {
+                    engine.transaction(|txn| {
+                        let count: u64 = txn.get_string("counter")?.map_or(0, |c| c.parse().unwrap());
+                        txn.set("counter", (count + 1).to_string())
+                    })?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(engine.get_string("counter")?, Some("100".to_owned()));
+    Ok(())
+}
+
+// Transactions should read their own writes and apply them all or none
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_transaction(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_transaction(SledKvsEngine::new(sled::open(temp_dir.path())?)?)
+}
+
+// A transaction should be run again if a key it read was written meanwhile
+#[test]
+fn transaction_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    let attempts = AtomicUsize::new(0);
+    store.transaction(|txn| {
+        let value = txn.get_string("key1")?.unwrap();
+        if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+            store.set("key1", "value1b")?;
+        }
+        // a write to a key the transaction does not use is no conflict
+        store.set("key2", format!("value2-{}", attempts.load(Ordering::SeqCst)))?;
+        txn.set("copy", value)
+    })?;
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    assert_eq!(store.get_string("copy")?, Some("value1b".to_owned()));
+    assert_eq!(store.get_string("key2")?, Some("value2-2".to_owned()));
+
+    // the transaction conflicts with the removal of a key it wrote
+    let attempts = AtomicUsize::new(0);
+    store.transaction(|txn| {
+        if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+            store.remove("copy")?;
+        }
+        txn.set("copy", "value")
+    })?;
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    Ok(())
+}
+
+
+fn check_transaction_gives_up<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("key1", "value1")?;
+    let attempts = AtomicUsize::new(0);
+    let res = engine.transaction(|txn| {
+        attempts.fetch_add(1, Ordering::SeqCst);
+        txn.get("key1")?;
+        // every attempt conflicts
+        engine.set("key1", "value1")?;
+        txn.set("key2", "value2")
+    });
+    match res {
+        Err(KvsError::Conflict) => {}
+        res => panic!("unexpected transaction result: {:?}", res),
+    }
+    assert_eq!(attempts.load(Ordering::SeqCst), 100);
+    assert_eq!(engine.get("key2")?, None);
+    Ok(())
+}
+
+// A transaction conflicting on every attempt should fail instead of running forever
+#[test]
+fn transaction_gives_up() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_transaction_gives_up(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_transaction_gives_up(SledKvsEngine::new(sled::open(temp_dir.path())?)?)
+}
+
+
+fn check_checkpoint<E: KvsEngine>(engine: E, dir: &TempDir) -> Result<()> {
+    for i in 0..100 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    engine.remove("key0")?;
+    let dest = dir.path().join("checkpoint");
+    engine.checkpoint(&dest)?;
+    engine.set("key1", "new1")?;
+    engine.set("key100", "value100")?;
+    assert!(engine.checkpoint(&dest).is_err());
+    Ok(())
+}
+
+// A checkpoint should hold the data as it was when it was taken
+#[test]
+fn checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest = temp_dir.path().join("checkpoint");
+    check_checkpoint(KvStore::open(temp_dir.path().join("data"))?, &temp_dir)?;
+    let store = KvStore::open(&dest)?;
+    assert_eq!(store.get("key0")?, None);
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key100")?, None);
+    assert_eq!(store.scan_prefix("key", ScanOptions::new())?.count(), 99);
+    assert_eq!(fs::read_to_string(dest.join("engine"))?, "kvs");
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest = temp_dir.path().join("checkpoint");
+    let db = sled::open(temp_dir.path().join("data"))?;
+    check_checkpoint(SledKvsEngine::new(db)?, &temp_dir)?;
+    let engine = SledKvsEngine::new(sled::open(&dest)?)?;
+    assert_eq!(engine.get("key0")?, None);
+    assert_eq!(engine.get_string("key1")?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key100")?, None);
+    assert_eq!(engine.scan_prefix("key", ScanOptions::new())?.count(), 99);
+    assert_eq!(fs::read_to_string(dest.join("engine"))?, "sled");
+    Ok(())
+}
+
+// Checkpoints should be consistent while writes and compactions go on
+#[test]
+fn checkpoint_during_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(16 * 1024)
+        .max_file_size(16 * 1024);
+    let store = KvStore::open_with_options(temp_dir.path().join("data"), &options)?;
+    // every batch sets all the keys to the same value
+    let write_all = |store: &KvStore, value: usize| -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for i in 0..50 {
+            batch.set(format!("key{}", i), value.to_string());
+        }
+        store.write_batch(batch)
+    };
+    write_all(&store, 0)?;
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for value in 1..500 {
+                write_all(&store, value)?;
+            }
+            Ok(())
+        })
+    };
+    for n in 0..10 {
+        let dest = temp_dir.path().join(format!("checkpoint{}", n));
+        store.checkpoint(&dest)?;
+        let checkpoint = KvStore::open_read_only(&dest)?;
+        let values: Vec<_> = checkpoint
+            .scan_prefix("key", ScanOptions::new())?
+            .map(|pair| pair.map(|(_, value)| value))
+            .collect::<Result<_>>()?;
+        assert_eq!(values.len(), 50);
+        assert!(values.iter().all(|value| *value == values[0]));
+    }
+    writer.join().unwrap()?;
+
+    // a read-only store is copied as well
+    drop(store);
+    let store = KvStore::open_read_only(temp_dir.path().join("data"))?;
+    store.checkpoint(temp_dir.path().join("copy"))?;
+    let copy = KvStore::open(temp_dir.path().join("copy"))?;
+    assert_eq!(copy.get_string("key0")?, Some("499".to_owned()));
+    Ok(())
+}
+
+
+// A dump of one engine should restore into the other, and damaged dumps be refused
+#[test]
+fn dump_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("kvs"))?;
+    for i in 0..2500 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set(vec![0xff, 0x00], vec![0, 159, 146, 150])?;
+    store.remove("key0")?;
+    let mut dumped = Vec::new();
+    assert_eq!(kvs::dump(&store, &mut dumped)?, 2500);
+
+    let sled_engine = SledKvsEngine::new(sled::open(temp_dir.path().join("sled"))?)?;
+    sled_engine.set("key1", "old")?;
+    sled_engine.set("other", "kept")?;
+    assert_eq!(kvs::restore(&sled_engine, dumped.as_slice())?, 2500);
+    assert_eq!(sled_engine.get("key0")?, None);
+    assert_eq!(sled_engine.get_string("key1")?, Some("value1".to_owned()));
+    assert_eq!(sled_engine.get(vec![0xff, 0x00])?, Some(vec![0, 159, 146, 150]));
+    assert_eq!(sled_engine.get_string("other")?, Some("kept".to_owned()));
+
+    let mut dumped_again = Vec::new();
+    assert_eq!(kvs::dump(&sled_engine, &mut dumped_again)?, 2501);
+    let restored = KvStore::open(temp_dir.path().join("restored"))?;
+    assert_eq!(kvs::restore(&restored, dumped_again.as_slice())?, 2501);
+    assert_eq!(restored.get_string("key2499")?, Some("value2499".to_owned()));
+
+    let truncated = &dumped[..dumped.len() - 10];
+    match kvs::restore(&restored, truncated) {
+        Err(KvsError::Corruption(_)) => {}
+        res => panic!("unexpected restore result: {:?}", res),
+    }
+    match kvs::restore(&restored, &b"not a dump at all"[..]) {
+        Err(KvsError::UnsupportedLogFormat(_)) => {}
+        res => panic!("unexpected restore result: {:?}", res),
+    }
+    Ok(())
+}
+
+fn check_dump_restore_ttl<S: KvsEngine, T: KvsEngine>(source: S, target: T) -> Result<()> {
+    source.set("plain", "value1")?;
+    source.set_with_ttl("long", "value2", Duration::from_secs(60))?;
+    source.set_with_ttl("short", "value3", Duration::from_millis(500))?;
+    source.set_with_ttl("expired", "value4", Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+    let mut dumped = Vec::new();
+    assert_eq!(kvs::dump(&source, &mut dumped)?, 3);
+
+    assert_eq!(kvs::restore(&target, dumped.as_slice())?, 3);
+    assert_eq!(target.get_string("short")?, Some("value3".to_owned()));
+    assert_eq!(target.get("expired")?, None);
+    thread::sleep(Duration::from_millis(600));
+    assert_eq!(target.get("short")?, None);
+    assert_eq!(target.get_string("long")?, Some("value2".to_owned()));
+    assert_eq!(target.get_string("plain")?, Some("value1".to_owned()));
+
+    // keys expired since the dump are not restored
+    let later = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(kvs::restore(&KvStore::open(later.path())?, dumped.as_slice())?, 2);
+    Ok(())
+}
+
+// Keys set with a TTL should expire at the same time once restored from a dump
+#[test]
+fn dump_restore_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_dump_restore_ttl(
+        KvStore::open(temp_dir.path().join("kvs"))?,
+        SledKvsEngine::new(sled::open(temp_dir.path().join("sled"))?)?,
+    )?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_dump_restore_ttl(
+        SledKvsEngine::new(sled::open(temp_dir.path().join("sled"))?)?,
+        KvStore::open(temp_dir.path().join("kvs"))?,
+    )
+}
+
+
+// Verify should account for every byte of the log files and report their damage
+#[test]
+fn verify() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("new{}", i))?;
+    }
+    store.remove("key99")?;
+    store.set_with_ttl("ttl", "value", Duration::from_millis(1))?;
+    assert!(matches!(KvStore::verify(temp_dir.path()), Err(KvsError::DirectoryInUse(_))));
+    drop(store);
+    thread::sleep(Duration::from_millis(10));
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(report.is_clean());
+    assert_eq!(report.keys, 99);
+    assert_eq!(report.expired_keys, 1);
+    let gen = &report.generations[0];
+    assert_eq!(gen.gen, 1);
+    assert_eq!(gen.keys, 99);
+    assert!(!gen.hint && !gen.orphaned);
+    assert_eq!(gen.size, 8 + gen.live_bytes + gen.garbage_bytes);
+    assert!(gen.garbage_bytes > 0);
+
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    let mut file = OpenOptions::new().append(true).open(&log)?;
+    file.write_all(b"not a record")?;
+    drop(file);
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(!report.is_clean());
+    let repair = report.generations[0].corruption.as_ref().unwrap();
+    assert_eq!(repair.offset, len);
+    assert_eq!(repair.bytes_dropped, 12);
+    assert_eq!(report.keys, 99);
+    // nothing is repaired
+    assert_eq!(fs::metadata(&log)?.len(), len + 12);
+    Ok(())
+}
+
+// Log files compacted while a snapshot was open, and never removed, should be
+// reported as orphaned
+#[test]
+fn verify_orphaned() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(16 * 1024);
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    store.set("removed", "value")?;
+    store.remove("removed")?;
+    let snapshot = store.snapshot()?;
+    for iter in 0..1000 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", iter))?;
+        }
+        if hint_files(temp_dir.path()).len() > 1 {
+            break;
+        }
+    }
+    drop(store);
+    // the files as left by a process exiting with the snapshot open, which keeps
+    // the directory locked until then
+    let copy_dir = TempDir::new().expect("unable to create temporary working directory");
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        fs::copy(&path, copy_dir.path().join(path.file_name().unwrap()))?;
+    }
+    drop(snapshot);
+
+    let report = KvStore::verify(copy_dir.path())?;
+    assert!(report.is_clean());
+    assert_eq!(report.keys, 100);
+    assert!(report.generations[0].orphaned);
+    assert_eq!(report.generations[0].live_bytes, 0);
+    let last = report.generations.last().unwrap();
+    assert!(!last.orphaned && !last.hint);
+    assert!(report.generations.iter().any(|gen| gen.hint && !gen.orphaned));
+    Ok(())
+}
+
+// Log files emptied by writes made after the last compaction are waiting for the
+// next one, and should not be reported as orphaned
+#[test]
+fn verify_awaiting_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(64 * 1024)
+        .max_file_size(1024)
+        .file_dead_ratio(0.9);
+    let store = KvStore::open_with_options(temp_dir.path(), &options)?;
+    // fills the first file, which holds no garbage when compactions run
+    for i in 0..20 {
+        store.set(format!("kept{}", i), "v".repeat(100))?;
+    }
+    for iter in 0..1000 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", iter))?;
+        }
+        if !hint_files(temp_dir.path()).is_empty() {
+            break;
+        }
+    }
+    // lets the compaction remove its input files
+    thread::sleep(Duration::from_millis(200));
+    for i in 0..20 {
+        store.set(format!("kept{}", i), "new")?;
+    }
+    drop(store);
+
+    let report = KvStore::verify(temp_dir.path())?;
+    let first = &report.generations[0];
+    assert_eq!(first.gen, 1);
+    assert_eq!(first.live_bytes, 0);
+    assert!(!first.orphaned);
+    assert!(report.generations.iter().all(|gen| !gen.orphaned));
+    Ok(())
+}
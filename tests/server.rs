@@ -0,0 +1,324 @@
+use kvs::thread_pool::*;
+use kvs::{
+    KvStore, KvsClient, KvsEngine, KvsError, KvsServer, Result, ScanOptions, SledKvsEngine,
+    WriteBatch,
+};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+
+// `run` should return once the shutdown handle is triggered, with writes persisted
+#[test]
+fn shutdown_stops_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4020";
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    );
+    let handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    drop(client);
+
+    handle.shutdown();
+    server_thread.join().unwrap()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+    Ok(())
+}
+
+
+// An idle client connection should not keep the server from shutting down
+#[test]
+fn shutdown_with_idle_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4021";
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )
+    .with_shutdown_timeout(Duration::from_secs(10));
+    let handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let start = Instant::now();
+    handle.shutdown();
+    server_thread.join().unwrap()?;
+    assert!(start.elapsed() < Duration::from_secs(10));
+    drop(client);
+    Ok(())
+}
+
+
+// Requesting a shutdown before the server runs makes `run` return immediately
+#[test]
+fn shutdown_before_run() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    );
+    server.shutdown_handle().shutdown();
+    server.run("127.0.0.1:4022")
+}
+
+
+// A batch sent by a client should be applied by both engines
+#[test]
+fn client_write_batch() -> Result<()> {
+    fn check<E: KvsEngine>(engine: E, addr: &'static str) -> Result<()> {
+        let server = KvsServer::new(engine.clone(), SharedQueueThreadPool::new(2)?);
+        let handle = server.shutdown_handle();
+        let server_thread = thread::spawn(move || server.run(addr));
+        thread::sleep(Duration::from_millis(500));
+
+        let mut client = KvsClient::connect(addr)?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        let mut batch = WriteBatch::new();
+        batch.remove("key1");
+        batch.set("key2".to_owned(), "value2".to_owned());
+        batch.set("key3".to_owned(), "value3".to_owned());
+        client.write_batch(batch)?;
+        assert_eq!(client.get("key1")?, None);
+        assert_eq!(client.get_string("key2")?, Some("value2".to_owned()));
+        assert_eq!(client.get_string("key3")?, Some("value3".to_owned()));
+        drop(client);
+
+        handle.shutdown();
+        server_thread.join().unwrap()
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path())?, "127.0.0.1:4023")?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(SledKvsEngine::new(sled::open(temp_dir.path())?)?, "127.0.0.1:4024")
+}
+
+
+// Binary keys and values should go through the protocol unchanged
+#[test]
+fn client_binary_keys_and_values() -> Result<()> {
+    fn check<E: KvsEngine>(engine: E, addr: &'static str) -> Result<()> {
+        let server = KvsServer::new(engine.clone(), SharedQueueThreadPool::new(2)?);
+        let handle = server.shutdown_handle();
+        let server_thread = thread::spawn(move || server.run(addr));
+        thread::sleep(Duration::from_millis(500));
+
+        let mut client = KvsClient::connect(addr)?;
+        client.set(vec![0xff, 0x00], vec![0, 159, 146, 150])?;
+        client.set(vec![0xff, 0x01], Vec::new())?;
+        assert_eq!(client.get(vec![0xff, 0x00])?, Some(vec![0, 159, 146, 150]));
+        assert_eq!(client.get(vec![0xff, 0x01])?, Some(Vec::new()));
+        assert!(client.get_string(vec![0xff, 0x00]).is_err());
+        assert_eq!(
+            client.scan_prefix(vec![0xff], ScanOptions::new())?,
+            vec![
+                (vec![0xff, 0x00], vec![0, 159, 146, 150]),
+                (vec![0xff, 0x01], Vec::new()),
+            ]
+        );
+        drop(client);
+
+        handle.shutdown();
+        server_thread.join().unwrap()?;
+        assert_eq!(engine.get(vec![0xff, 0x00])?, Some(vec![0, 159, 146, 150]));
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path())?, "127.0.0.1:4025")?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(SledKvsEngine::new(sled::open(temp_dir.path())?)?, "127.0.0.1:4026")
+}
+
+
+// A transaction should only apply its writes on commit, unless it conflicts
+#[test]
+fn client_transaction() -> Result<()> {
+    fn check<E: KvsEngine>(engine: E, addr: &'static str) -> Result<()> {
+        let server = KvsServer::new(engine.clone(), SharedQueueThreadPool::new(2)?);
+        let handle = server.shutdown_handle();
+        let server_thread = thread::spawn(move || server.run(addr));
+        thread::sleep(Duration::from_millis(500));
+
+        let mut client = KvsClient::connect(addr)?;
+        let mut other = KvsClient::connect(addr)?;
+        client.set("key1", "value1")?;
+        client.begin()?;
+        client.set("key2", "value2")?;
+        client.remove("key1")?;
+        assert!(client.remove("key3").is_err());
+        assert!(client.scan_prefix("key", ScanOptions::new()).is_err());
+        assert_eq!(client.get("key1")?, None);
+        assert_eq!(other.get_string("key1")?, Some("value1".to_owned()));
+        assert_eq!(other.get("key2")?, None);
+        client.commit()?;
+        assert_eq!(other.get("key1")?, None);
+        assert_eq!(other.get_string("key2")?, Some("value2".to_owned()));
+
+        client.begin()?;
+        client.set("key3", "value3")?;
+        client.rollback()?;
+        assert_eq!(client.get("key3")?, None);
+        assert!(client.commit().is_err());
+
+        // a key read by the transaction is written by another client
+        client.begin()?;
+        assert_eq!(client.get_string("key2")?, Some("value2".to_owned()));
+        other.set("key2", "value2b")?;
+        client.set("key3", "value3")?;
+        match client.commit() {
+            Err(KvsError::Conflict) => {}
+            res => panic!("unexpected commit result: {:?}", res),
+        }
+        assert_eq!(client.get("key3")?, None);
+
+        let mut attempts = 0;
+        client.transaction(|client| {
+            attempts += 1;
+            let value = client.get_string("key2")?.unwrap();
+            if attempts == 1 {
+                other.set("key2", "value2c")?;
+            }
+            client.set("key3", value)
+        })?;
+        assert_eq!(attempts, 2);
+        assert_eq!(client.get_string("key3")?, Some("value2c".to_owned()));
+        drop(client);
+        drop(other);
+
+        handle.shutdown();
+        server_thread.join().unwrap()
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path())?, "127.0.0.1:4027")?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(SledKvsEngine::new(sled::open(temp_dir.path())?)?, "127.0.0.1:4028")
+}
+
+
+// Checkpoints should only be written under the checkpoint directory of the server
+#[test]
+fn checkpoint_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let checkpoint_dir = temp_dir.path().join("checkpoints");
+    std::fs::create_dir(&checkpoint_dir)?;
+    let addr = "127.0.0.1:4019";
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path().join("data"))?,
+        SharedQueueThreadPool::new(2)?,
+    )
+    .with_checkpoint_dir(&checkpoint_dir);
+    let handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1", "value1")?;
+    client.checkpoint("backup")?;
+    assert_eq!(
+        KvStore::open_read_only(checkpoint_dir.join("backup"))?.get_string("key1")?,
+        Some("value1".to_owned())
+    );
+    let escape = temp_dir.path().join("escape");
+    assert!(client.checkpoint(&escape).is_err());
+    assert!(client.checkpoint("../escape").is_err());
+    assert!(client.checkpoint("backup/../../escape").is_err());
+    assert!(client.checkpoint("").is_err());
+    assert!(!escape.exists());
+    drop(client);
+    handle.shutdown();
+    server_thread.join().unwrap()?;
+
+    // checkpoints are refused when no checkpoint directory is set
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path().join("data"))?,
+        SharedQueueThreadPool::new(2)?,
+    );
+    let handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+    let mut client = KvsClient::connect(addr)?;
+    assert!(client.checkpoint("other").is_err());
+    drop(client);
+    handle.shutdown();
+    server_thread.join().unwrap()
+}
+
+
+// Scans larger than a response should come back whole
+#[test]
+fn client_large_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4031";
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    );
+    let handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    let value = vec![7; 512 * 1024];
+    for i in 0..5 {
+        client.set(format!("key{}", i), value.clone())?;
+    }
+    let pairs = client.scan_prefix("key", ScanOptions::new())?;
+    assert_eq!(pairs.len(), 5);
+    assert!(pairs.iter().all(|(_, v)| *v == value));
+    let pairs = client.scan_prefix("key", ScanOptions::new().reverse(true).limit(3))?;
+    assert_eq!(pairs.len(), 3);
+    assert_eq!(pairs[0].0, b"key4");
+    drop(client);
+
+    handle.shutdown();
+    server_thread.join().unwrap()
+}
+
+
+// A transaction left open past the timeout should be rolled back along with
+// its connection
+#[test]
+fn transaction_timeout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4032";
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )
+    .with_transaction_timeout(Duration::from_millis(500));
+    let handle = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.begin()?;
+    client.set("key1", "value1")?;
+    thread::sleep(Duration::from_millis(1000));
+    assert!(client.commit().is_err());
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1")?, None);
+    // requests in time keep the transaction open
+    client.begin()?;
+    client.set("key1", "value1")?;
+    thread::sleep(Duration::from_millis(100));
+    client.commit()?;
+    assert_eq!(client.get_string("key1")?, Some("value1".to_owned()));
+    drop(client);
+
+    handle.shutdown();
+    server_thread.join().unwrap()
+}